@@ -1,10 +1,16 @@
+use crate::backup::Backups;
 use crate::db::DbPool;
-use crate::poll::PollID;
+use crate::poll::{import, PollID};
+use crate::security::{self, CsrfToken};
+use crate::session::{AdminSession, SessionConfig, SessionScope};
+use crate::webhook::{self, Webhooks};
 use crate::*;
 use askama::Template;
 use serde::Deserialize;
+use subtle::ConstantTimeEq;
 
-use actix_web::{web, HttpResponse, Result};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
 
 pub struct AdminToken(pub Option<String>);
 
@@ -18,39 +24,169 @@ enum AdminAction {
     ResetLimits,
     /// Lists all polls currently in the database.
     ListPolls,
+    /// Saves a snapshot of the database to the backup directory.
+    CreateBackup,
     /// Resets all votes on a poll. Poll specific.
     ResetVotes,
     /// Removes a poll from the database. Poll specific.
     DeletePoll,
+    /// Replaces the poll's admin token with a new one, invalidating the old one. Poll specific.
+    RegenerateToken,
 }
 
 #[derive(Deserialize)]
 pub struct AdminParams {
-    token: String,
+    /// Can be omitted when logged in or sent in an "Authorization: Bearer" header instead
+    token: Option<String>,
     action: AdminAction,
 }
 
+#[derive(Deserialize)]
+pub struct ImportParams {
+    /// Can be omitted when logged in or sent in an "Authorization: Bearer" header instead
+    token: Option<String>,
+    format: String,
+    ballots: String,
+}
+
+/// The largest file of ballots that can be imported at once
+pub const MAX_IMPORT_SIZE: usize = 256 * 1024;
+
+/// Changes to a poll's webhooks, see webhook::Webhooks
+#[derive(Deserialize, Debug)]
+enum WebhookAction {
+    /// Registers the URL in `url`
+    AddWebhook,
+    /// Removes the webhook with the id in `id`
+    RemoveWebhook,
+}
+
+#[derive(Deserialize)]
+pub struct WebhookParams {
+    /// Can be omitted when logged in or sent in an "Authorization: Bearer" header instead
+    token: Option<String>,
+    action: WebhookAction,
+    url: Option<String>,
+    id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct LoginParams {
+    token: String,
+}
+
+/// Checks whether a request can execute admin actions in the given scope:
+/// either it contains the correct admin token (in the params or an "Authorization: Bearer"
+/// header), or it carries a valid session cookie.
+fn authorize(
+    req: &HttpRequest,
+    sessions: &SessionConfig,
+    scope: SessionScope,
+    admin_token: &str,
+    token: Option<&str>,
+) -> Result<(), UserError> {
+    let token = token.or_else(|| security::bearer_token(req));
+    if let Some(token) = token {
+        return check_token(req, token, admin_token);
+    }
+
+    match AdminSession::from_request(req, &sessions.key, scope, admin_token) {
+        Some(_) => Ok(()),
+        None => Err(UserError::InvalidAdminToken),
+    }
+}
+
+/// Compares the token sent by the user to the admin token (in constant time).
+/// Failed attempts count towards the admin login rate limit.
+fn check_token(req: &HttpRequest, token: &str, admin_token: &str) -> Result<(), UserError> {
+    rate::check_limited(req, rate::Route::AdminLogin)?;
+    if !bool::from(token.as_bytes().ct_eq(admin_token.as_bytes())) {
+        rate::limit(req, rate::Route::AdminLogin, None)?;
+        return Err(UserError::InvalidAdminToken);
+    }
+    Ok(())
+}
+
+/// Redirects to the given admin page, setting the cookie
+fn redirect_with_cookie(
+    location: &str,
+    cookie: actix_web::cookie::Cookie<'static>,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, location))
+        .cookie(cookie)
+        .finish())
+}
+
 /// Handles the general administration webpage
-pub async fn handle_admin() -> Result<HttpResponse> {
-    return_html!(include_str!("../static/admin.html"))
+/// Shows the admin actions if logged in, a login form otherwise.
+pub async fn handle_admin(
+    req: HttpRequest,
+    admin_token: web::Data<AdminToken>,
+    sessions: web::Data<SessionConfig>,
+    csrf: CsrfToken,
+) -> Result<HttpResponse> {
+    let session = admin_token.0.as_ref().and_then(|token| {
+        AdminSession::from_request(&req, &sessions.key, SessionScope::Server, token)
+    });
+    let content = templates::AdminTemplate {
+        session: session.as_ref(),
+        csrf_token: &csrf.0,
+    }
+    .render()
+    .map_err(|e| UserError::InternalError(e.into()))?;
+
+    return_html!(content)
 }
 
-/// Handles the general administration webpage callback
+/// Handles logging in to the general administration webpage
 /// Params:
 ///  - token: The admin token, should match the POLL_ADMIN_TOKEN environmental variable
+///
+/// Sets a session cookie and redirects to the admin page.
+pub async fn handle_admin_login(
+    req: HttpRequest,
+    params: web::Form<LoginParams>,
+    admin_token: web::Data<AdminToken>,
+    sessions: web::Data<SessionConfig>,
+) -> Result<HttpResponse> {
+    let admin_token = admin_token.0.as_ref().ok_or(UserError::AdminOff)?;
+    check_token(&req, &params.token, admin_token)?;
+
+    log::warn!("Admin logged in");
+    let session = AdminSession::new(SessionScope::Server, sessions.length);
+    redirect_with_cookie("/admin", session.cookie(&sessions.key, admin_token))
+}
+
+/// Handles logging out of the general administration webpage
+pub async fn handle_admin_logout() -> Result<HttpResponse> {
+    redirect_with_cookie("/admin", AdminSession::removal_cookie(SessionScope::Server))
+}
+
+/// Handles the general administration webpage callback
+/// Params:
+///  - token: The admin token, should match the POLL_ADMIN_TOKEN environmental variable.
+///    Can be omitted if logged in.
 ///  - action: an AdminAction enum member, specifies the action to be executed.
+///
 /// Only the non-poll-specific administration actions can be executed from here.
 pub async fn handle_admin_action(
+    req: HttpRequest,
     db: web::Data<DbPool>,
     params: web::Form<AdminParams>,
     limits: web::Data<rate::LimitStore>,
     admin_token: web::Data<AdminToken>,
+    sessions: web::Data<SessionConfig>,
+    backups: web::Data<Option<Backups>>,
 ) -> Result<HttpResponse> {
-    match admin_token.0.as_ref() {
-        None => return Err(UserError::AdminOff.into()),
-        Some(t) if t != params.token.as_str() => return Err(UserError::InvalidAdminToken.into()),
-        _ => (),
-    }
+    let admin_token = admin_token.0.as_ref().ok_or(UserError::AdminOff)?;
+    authorize(
+        &req,
+        &sessions,
+        SessionScope::Server,
+        admin_token,
+        params.token.as_deref(),
+    )?;
 
     match params.action {
         AdminAction::PurgeDatabase => {
@@ -69,6 +205,15 @@ pub async fn handle_admin_action(
                 .map_err(|e| UserError::InternalError(e.into()))?;
             return return_html!(content);
         }
+        AdminAction::CreateBackup => {
+            let backups = backups.as_ref().as_ref().ok_or(UserError::BackupsOff)?;
+            let path = backups
+                .create(&db)
+                .await
+                .map_err(UserError::InternalError)?;
+            log::warn!("Backup saved to {:?}", path);
+            return return_html!(format!("Backup saved to {:?}", path));
+        }
         _ => return Err(UserError::InvalidAdminAction.into()),
     }
 
@@ -76,30 +221,111 @@ pub async fn handle_admin_action(
 }
 
 /// Handles the poll-specific administration webpage
-pub async fn handle_poll_admin(_poll_id: web::Path<String>) -> Result<HttpResponse> {
-    return_html!(include_str!("../static/poll_admin.html"))
+/// Shows the admin actions if logged in, a login form otherwise.
+pub async fn handle_poll_admin(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+    sessions: web::Data<SessionConfig>,
+    webhooks: web::Data<Option<Webhooks>>,
+    csrf: CsrfToken,
+) -> Result<HttpResponse> {
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    let poll = db::get_poll(&db, poll_id).await?;
+
+    let session = AdminSession::from_request(
+        &req,
+        &sessions.key,
+        SessionScope::Poll(poll_id.index()),
+        &poll.data.admin_link,
+    );
+    let (audit_log, webhook_list, deliveries) = match session {
+        Some(_) if webhooks.is_some() => (
+            db::audit_log(&db, poll_id).await?,
+            db::get_webhooks(&db, poll_id).await?,
+            db::webhook_deliveries(&db, poll_id).await?,
+        ),
+        Some(_) => (db::audit_log(&db, poll_id).await?, Vec::new(), Vec::new()),
+        None => (Vec::new(), Vec::new(), Vec::new()),
+    };
+    let content = templates::PollAdminTemplate {
+        poll: &poll.data,
+        session: session.as_ref(),
+        csrf_token: &csrf.0,
+        audit_log: &audit_log,
+        webhooks: webhooks.is_some().then_some(webhook_list.as_slice()),
+        deliveries: &deliveries,
+    }
+    .render()
+    .map_err(|e| UserError::InternalError(e.into()))?;
+
+    return_html!(content)
+}
+
+/// Handles logging in to the poll-specific administration webpage
+/// Params:
+///  - token: The poll's admin token
+///
+/// Sets a session cookie and redirects to the poll admin page.
+pub async fn handle_poll_admin_login(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+    params: web::Form<LoginParams>,
+    sessions: web::Data<SessionConfig>,
+) -> Result<HttpResponse> {
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    let poll = db::get_poll(&db, poll_id).await?;
+
+    check_token(&req, &params.token, &poll.data.admin_link)?;
+
+    let session = AdminSession::new(SessionScope::Poll(poll_id.index()), sessions.length);
+    redirect_with_cookie(
+        req.url_for("admin", [poll_id.to_string()])
+            .unwrap()
+            .as_str(),
+        session.cookie(&sessions.key, &poll.data.admin_link),
+    )
+}
+
+/// Handles logging out of the poll-specific administration webpage
+pub async fn handle_poll_admin_logout(
+    req: HttpRequest,
+    poll_id: web::Path<String>,
+) -> Result<HttpResponse> {
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+
+    redirect_with_cookie(
+        req.url_for("admin", [poll_id.to_string()])
+            .unwrap()
+            .as_str(),
+        AdminSession::removal_cookie(SessionScope::Poll(poll_id.index())),
+    )
 }
 
 /// Handles the poll-specific administration webpage callback
 /// Params:
-///  - token: The admin token, should match the POLL_ADMIN_TOKEN environmental variable
+///  - token: The poll's admin token. Can be omitted if logged in.
 ///  - action: an AdminAction enum member, specifies the action to be executed.
+///
 /// Only the poll-specific administration actions can be executed from here.
 pub async fn handle_poll_admin_action(
+    req: HttpRequest,
     db: web::Data<DbPool>,
     poll_id: web::Path<String>,
     params: web::Form<AdminParams>,
+    sessions: web::Data<SessionConfig>,
+    hub: web::Data<live::Hub>,
+    webhooks: web::Data<Option<Webhooks>>,
 ) -> Result<HttpResponse> {
     let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
     let mut poll = db::get_poll(&db, poll_id).await?;
 
-    if params.token != poll.data.admin_link.as_str() {
-        log::warn!(
-            "Invalid poll token: {}, {}",
-            params.token,
-            poll.data.admin_link.as_str()
-        );
-        return Err(UserError::InvalidAdminToken.into());
+    let scope = SessionScope::Poll(poll_id.index());
+    let token = params.token.as_deref();
+    if let Err(e) = authorize(&req, &sessions, scope, &poll.data.admin_link, token) {
+        log::warn!("Invalid admin token for poll id: {}", poll_id);
+        return Err(e.into());
     }
 
     match params.action {
@@ -107,12 +333,163 @@ pub async fn handle_poll_admin_action(
             poll.data.voters = 0;
             poll.format.reset();
             db::update_poll(&db, &poll).await?;
+            db::delete_ballots(&db, poll.data.id).await?;
+            let entry = templates::AuditEntry::new("ResetVotes", "All votes removed".into());
+            db::log_action(&db, poll.data.id, &entry).await?;
+            hub.publish(&poll);
+            if let Some(webhooks) = webhooks.as_ref() {
+                webhooks.notify(&db, &poll, webhook::Event::Reset).await;
+            }
         }
         AdminAction::DeletePoll => {
+            // The webhooks are deleted with the poll
+            let webhook_list = db::get_webhooks(&db, poll.data.id).await?;
             db::delete_poll(&db, poll.data.id).await?;
+            if let Some(webhooks) = webhooks.as_ref() {
+                webhooks.send_all(&db, &poll, webhook::Event::Delete, webhook_list);
+            }
+        }
+        AdminAction::RegenerateToken => {
+            let admin_token = util::random_base64_u64();
+            db::update_admin_token(&db, poll.data.id, &admin_token).await?;
+            log::warn!("Admin token regenerated for poll id: {}", poll.data.id);
+            let entry = templates::AuditEntry::new("RegenerateToken", "New admin token".into());
+            db::log_action(&db, poll.data.id, &entry).await?;
+
+            let content = templates::TokenRegeneratedTemplate {
+                admin_link: req
+                    .url_for("admin", [poll.data.id.to_string()])
+                    .unwrap()
+                    .as_str(),
+                admin_token: admin_token.as_str(),
+            }
+            .render()
+            .map_err(|e| UserError::InternalError(e.into()))?;
+            return return_html!(content);
         }
         _ => return Err(UserError::InvalidAdminAction.into()),
     }
 
     return_html!(format!("Action executed: {:?}", params.action))
 }
+
+/// Handles importing ballots collected outside the server into a poll
+/// Params:
+///  - token: The poll's admin token. Can be omitted if logged in.
+///  - format: The format of the ballots, see poll::import::BallotFormat
+///  - ballots: The contents of the file of ballots
+///
+/// The ballots are validated like votes; if any of them is invalid, none are imported.
+pub async fn handle_poll_import(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+    params: web::Form<ImportParams>,
+    sessions: web::Data<SessionConfig>,
+    hub: web::Data<live::Hub>,
+    webhooks: web::Data<Option<Webhooks>>,
+) -> Result<HttpResponse> {
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    let mut poll = db::get_poll(&db, poll_id).await?;
+
+    let scope = SessionScope::Poll(poll_id.index());
+    authorize(
+        &req,
+        &sessions,
+        scope,
+        &poll.data.admin_link,
+        params.token.as_deref(),
+    )?;
+
+    let format = import::BallotFormat::try_parse(&params.format).map_err(UserError::Import)?;
+    let state = poll.format.export_state();
+    let options: Vec<&str> = state.options.iter().map(|o| o.name.as_str()).collect();
+    let votes = import::read_votes(poll.data.ptype, &options, format, &params.ballots)
+        .map_err(UserError::Import)?;
+    let ballots = import::register_votes(&mut poll, &votes).map_err(UserError::Import)?;
+
+    let entry = templates::AuditEntry::new(
+        "ImportBallots",
+        format!(
+            "{} ballots in {} format",
+            votes.len(),
+            params.format.to_uppercase()
+        ),
+    );
+    db::record_import(&db, &poll, &ballots, &entry).await?;
+    hub.publish(&poll);
+    if let Some(webhooks) = webhooks.as_ref() {
+        webhooks.notify(&db, &poll, webhook::Event::Import).await;
+    }
+    log::info!("Imported {} ballots into poll id: {}", votes.len(), poll_id);
+
+    return_html!(format!("Imported {} ballots", votes.len()))
+}
+
+/// Handles adding and removing the poll's webhooks
+/// Params:
+///  - token: The poll's admin token. Can be omitted if logged in.
+///  - action: a WebhookAction enum member
+///  - url: The URL of the webhook to add
+///  - id: The id of the webhook to remove
+///
+/// Returns the new webhook's signing secret when adding one.
+pub async fn handle_poll_webhooks(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+    params: web::Form<WebhookParams>,
+    sessions: web::Data<SessionConfig>,
+    webhooks: web::Data<Option<Webhooks>>,
+) -> Result<HttpResponse> {
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    let poll = db::get_poll(&db, poll_id).await?;
+
+    let scope = SessionScope::Poll(poll_id.index());
+    authorize(
+        &req,
+        &sessions,
+        scope,
+        &poll.data.admin_link,
+        params.token.as_deref(),
+    )?;
+    let webhooks = webhooks.as_ref().as_ref().ok_or(UserError::WebhooksOff)?;
+
+    match params.action {
+        WebhookAction::AddWebhook => {
+            let url = params
+                .url
+                .as_deref()
+                .map(str::trim)
+                .ok_or_else(|| UserError::Webhook(anyhow::anyhow!("No URL given")))?;
+            if db::get_webhooks(&db, poll_id).await?.len() >= webhook::MAX_WEBHOOKS {
+                return Err(UserError::Webhook(anyhow::anyhow!(
+                    "A poll can have at most {} webhooks",
+                    webhook::MAX_WEBHOOKS
+                ))
+                .into());
+            }
+            webhooks.check_url(url).await.map_err(UserError::Webhook)?;
+
+            let secret = webhook::generate_secret();
+            let id = db::add_webhook(&db, poll_id, url, &secret).await?;
+            let entry =
+                templates::AuditEntry::new("AddWebhook", format!("Webhook {}: {}", id, url));
+            db::log_action(&db, poll_id, &entry).await?;
+
+            return_html!(format!("Webhook {} added, signing secret: {}", id, secret))
+        }
+        WebhookAction::RemoveWebhook => {
+            let id = params
+                .id
+                .ok_or_else(|| UserError::Webhook(anyhow::anyhow!("No webhook id given")))?;
+            if !db::remove_webhook(&db, poll_id, id).await? {
+                return Err(UserError::Webhook(anyhow::anyhow!("No such webhook")).into());
+            }
+            let entry = templates::AuditEntry::new("RemoveWebhook", format!("Webhook {}", id));
+            db::log_action(&db, poll_id, &entry).await?;
+
+            return_html!(format!("Webhook {} removed", id))
+        }
+    }
+}
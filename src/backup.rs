@@ -0,0 +1,91 @@
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+use crate::db::{self, DbPool};
+
+/// The file names of backups are made of this prefix, the time of the backup and ".db"
+const BACKUP_PREFIX: &str = "pollinator-";
+
+/// Database backups saved to a directory, by the admin action and on a schedule.
+/// Only the newest backups are kept.
+pub struct Backups {
+    dir: PathBuf,
+    /// The number of backups kept, 0 keeps all of them
+    keep: usize,
+}
+
+impl Backups {
+    pub fn new(dir: PathBuf, keep: usize) -> Self {
+        Backups { dir, keep }
+    }
+
+    /// Saves a snapshot of the database and removes the oldest backups.
+    /// Returns the path of the new backup.
+    pub async fn create(&self, pool: &DbPool) -> anyhow::Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create the backup directory {:?}", self.dir))?;
+        // The timestamps sort in the same order as the backups were made
+        let path = self.dir.join(format!(
+            "{}{}.db",
+            BACKUP_PREFIX,
+            chrono::Utc::now().format("%Y%m%d-%H%M%S%.3f")
+        ));
+        db::backup(pool, &path).await?;
+        let removed = self.prune()?;
+        if removed > 0 {
+            log::info!("Removed {} old backups.", removed);
+        }
+        Ok(path)
+    }
+
+    /// Removes all but the newest `keep` backups, returns the number of removed files
+    fn prune(&self) -> anyhow::Result<usize> {
+        if self.keep == 0 {
+            return Ok(0);
+        }
+        let mut backups = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if is_backup(&path) {
+                backups.push(path);
+            }
+        }
+        backups.sort();
+        let old = backups.len().saturating_sub(self.keep);
+        for path in &backups[..old] {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove an old backup {:?}", path))?;
+        }
+        Ok(old)
+    }
+}
+
+/// Whether the file was created by Backups::create
+fn is_backup(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with(BACKUP_PREFIX) && name.ends_with(".db"))
+        .unwrap_or(false)
+}
+
+#[test]
+fn test_prune() {
+    let dir = std::env::temp_dir().join(format!("pollinator-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in [
+        "pollinator-20230101-120000.000.db",
+        "pollinator-20230102-120000.000.db",
+        "pollinator-20230103-120000.000.db",
+        "other.db",
+    ] {
+        std::fs::write(dir.join(name), "").unwrap();
+    }
+
+    let backups = Backups::new(dir.clone(), 2);
+    assert_eq!(backups.prune().unwrap(), 1);
+    assert!(!dir.join("pollinator-20230101-120000.000.db").exists());
+    assert!(dir.join("pollinator-20230103-120000.000.db").exists());
+    assert!(dir.join("other.db").exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
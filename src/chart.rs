@@ -0,0 +1,348 @@
+//! SVG charts of poll results, rendered on the server so that they work without
+//! JavaScript and can be saved as standalone images.
+
+use std::fmt::Write;
+
+use crate::poll::{Ballot, PortableState};
+
+const WIDTH: f64 = 640.0;
+const ROW_HEIGHT: f64 = 28.0;
+const BAR_HEIGHT: f64 = 18.0;
+const LABEL_WIDTH: f64 = 180.0;
+const VALUE_WIDTH: f64 = 90.0;
+const PADDING: f64 = 12.0;
+/// Longer option names are shortened, as SVG text doesn't wrap
+const MAX_LABEL_CHARS: usize = 24;
+
+const BACKGROUND: &str = "#111";
+const FOREGROUND: &str = "#eee";
+const PALETTE: &[&str] = &[
+    "#55f", "#f55", "#5c5", "#fb3", "#c5f", "#5cf", "#f8c", "#ca8", "#8a8", "#aaa",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChartKind {
+    /// A horizontal bar for every option, the default
+    Bar,
+    /// The share of votes or points of every option
+    Pie,
+    /// A bar for every option split by the places voters gave it, for ranked polls
+    Stacked,
+}
+
+impl ChartKind {
+    pub fn try_parse(s: &str) -> Option<Self> {
+        match s {
+            "bar" => Some(ChartKind::Bar),
+            "pie" => Some(ChartKind::Pie),
+            "stacked" => Some(ChartKind::Stacked),
+            _ => None,
+        }
+    }
+}
+
+/// Renders a bar chart of the tallies, the highest first
+pub fn bar(title: &str, state: &PortableState) -> String {
+    let options = sorted(state);
+    let max = options.iter().map(|(_, value)| *value).fold(0.0, f64::max);
+    let bar_width = WIDTH - LABEL_WIDTH - VALUE_WIDTH - 2.0 * PADDING;
+
+    let mut svg = Svg::new(title, ROW_HEIGHT * options.len() as f64);
+    for (row, (name, value)) in options.iter().enumerate() {
+        let y = svg.top + ROW_HEIGHT * row as f64;
+        let width = if max > 0.0 {
+            value / max * bar_width
+        } else {
+            0.0
+        };
+        svg.label(name, y);
+        svg.rect(
+            LABEL_WIDTH + PADDING,
+            y,
+            width,
+            PALETTE[0],
+            &format_value(*value),
+        );
+        svg.text(
+            LABEL_WIDTH + PADDING + width + 6.0,
+            y + BAR_HEIGHT - 4.0,
+            "start",
+            &format_value(*value),
+        );
+    }
+    svg.finish()
+}
+
+/// Renders a pie chart of the tallies with a legend, the highest first
+pub fn pie(title: &str, state: &PortableState) -> String {
+    const RADIUS: f64 = 110.0;
+
+    let options = sorted(state);
+    let total: f64 = options.iter().map(|(_, value)| value).sum();
+    let legend_height = ROW_HEIGHT * options.len() as f64;
+
+    let mut svg = Svg::new(title, legend_height.max(2.0 * RADIUS + PADDING));
+    let (cx, cy) = (PADDING + RADIUS, svg.top + RADIUS);
+
+    if total <= 0.0 {
+        let _ = write!(
+            svg.body,
+            r##"<circle cx="{cx}" cy="{cy}" r="{RADIUS}" fill="#444"/>"##
+        );
+        svg.text(cx, cy + 5.0, "middle", "No votes yet");
+    }
+
+    let mut angle: f64 = 0.0;
+    for (index, (name, value)) in options.iter().enumerate() {
+        let color = PALETTE[index % PALETTE.len()];
+        let share = if total > 0.0 { value / total } else { 0.0 };
+        let tooltip = format!("{}: {}", name, format_value(*value));
+
+        if share >= 1.0 {
+            let _ = write!(
+                svg.body,
+                r#"<circle cx="{cx}" cy="{cy}" r="{RADIUS}" fill="{color}"><title>{}</title></circle>"#,
+                escape(&tooltip)
+            );
+        } else if share > 0.0 {
+            let end = angle + share * std::f64::consts::TAU;
+            // Angles start at the top and go clockwise
+            let point = |a: f64| (cx + RADIUS * a.sin(), cy - RADIUS * a.cos());
+            let (x1, y1) = point(angle);
+            let (x2, y2) = point(end);
+            let large = (share > 0.5) as u8;
+            let _ = write!(
+                svg.body,
+                r#"<path d="M{cx:.2},{cy:.2}L{x1:.2},{y1:.2}A{RADIUS},{RADIUS} 0 {large} 1 {x2:.2},{y2:.2}Z" fill="{color}"><title>{}</title></path>"#,
+                escape(&tooltip)
+            );
+            angle = end;
+        }
+
+        let x = 2.0 * (PADDING + RADIUS);
+        let y = svg.top + ROW_HEIGHT * index as f64;
+        svg.rect(x, y, BAR_HEIGHT, color, &tooltip);
+        svg.text(
+            x + BAR_HEIGHT + 8.0,
+            y + BAR_HEIGHT - 4.0,
+            "start",
+            &format!(
+                "{} - {} ({:.1}%)",
+                shorten(name),
+                format_value(*value),
+                share * 100.0
+            ),
+        );
+    }
+    svg.finish()
+}
+
+/// Renders a bar for every option split into the number of voters that gave
+/// it each place, in the order the options were created in.
+pub fn stacked(title: &str, state: &PortableState, ballots: &[Ballot]) -> String {
+    let n = state.options.len();
+    // places[option][place] is the number of ballots giving the option that place
+    let mut places = vec![vec![0u64; n]; n];
+    for ballot in ballots {
+        for (option, place) in ballot.0.iter().enumerate().take(n) {
+            if let Some(count) = places[option].get_mut(*place as usize) {
+                *count += 1;
+            }
+        }
+    }
+    let bar_width = WIDTH - LABEL_WIDTH - 2.0 * PADDING;
+    let legend_rows = n.div_ceil(5);
+
+    let mut svg = Svg::new(title, ROW_HEIGHT * (n + legend_rows) as f64);
+    for (row, option) in state.options.iter().enumerate() {
+        let y = svg.top + ROW_HEIGHT * row as f64;
+        svg.label(&option.name, y);
+        let mut x = LABEL_WIDTH + PADDING;
+        for (place, count) in places[row].iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let width = *count as f64 / ballots.len() as f64 * bar_width;
+            let tooltip = format!("{}: {} voters, {}", option.name, count, ordinal(place + 1));
+            svg.rect(x, y, width, PALETTE[place % PALETTE.len()], &tooltip);
+            x += width;
+        }
+    }
+    if ballots.is_empty() {
+        svg.text(
+            LABEL_WIDTH + PADDING,
+            svg.top + BAR_HEIGHT - 4.0,
+            "start",
+            "No ballots yet",
+        );
+    }
+    for place in 0..n {
+        let x = PADDING + (place % 5) as f64 * 110.0;
+        let y = svg.top + ROW_HEIGHT * (n + place / 5) as f64;
+        let name = format!("{} place", ordinal(place + 1));
+        svg.rect(x, y, BAR_HEIGHT, PALETTE[place % PALETTE.len()], &name);
+        svg.text(x + BAR_HEIGHT + 6.0, y + BAR_HEIGHT - 4.0, "start", &name);
+    }
+    svg.finish()
+}
+
+/// An SVG document being built, with a title on top
+struct Svg {
+    body: String,
+    /// Where the content below the title starts
+    top: f64,
+}
+
+impl Svg {
+    fn new(title: &str, content_height: f64) -> Self {
+        let top = PADDING + 32.0;
+        let height = top + content_height + PADDING;
+        let mut body = String::new();
+        let _ = write!(
+            body,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{height}" viewBox="0 0 {WIDTH} {height}" font-family="sans-serif" font-size="14">"#,
+        );
+        let _ = write!(
+            body,
+            r#"<title>{0}</title><rect width="100%" height="100%" fill="{BACKGROUND}"/><text x="{PADDING}" y="{1}" fill="{FOREGROUND}" font-size="18" font-weight="bold">{0}</text>"#,
+            escape(title),
+            PADDING + 18.0,
+        );
+        Svg { body, top }
+    }
+
+    /// An option's name, to the left of its bar
+    fn label(&mut self, name: &str, y: f64) {
+        let _ = write!(
+            self.body,
+            r#"<text x="{}" y="{}" fill="{FOREGROUND}" text-anchor="end"><title>{}</title>{}</text>"#,
+            LABEL_WIDTH,
+            y + BAR_HEIGHT - 4.0,
+            escape(name),
+            escape(&shorten(name)),
+        );
+    }
+
+    fn rect(&mut self, x: f64, y: f64, width: f64, color: &str, tooltip: &str) {
+        let _ = write!(
+            self.body,
+            r#"<rect x="{x:.2}" y="{y:.2}" width="{width:.2}" height="{BAR_HEIGHT}" fill="{color}"><title>{}</title></rect>"#,
+            escape(tooltip),
+        );
+    }
+
+    fn text(&mut self, x: f64, y: f64, anchor: &str, text: &str) {
+        let _ = write!(
+            self.body,
+            r#"<text x="{x:.2}" y="{y:.2}" fill="{FOREGROUND}" text-anchor="{anchor}">{}</text>"#,
+            escape(text),
+        );
+    }
+
+    fn finish(mut self) -> String {
+        self.body.push_str("</svg>");
+        self.body
+    }
+}
+
+/// The options' names and tallies, the highest first
+fn sorted(state: &PortableState) -> Vec<(&str, f64)> {
+    let mut options: Vec<_> = state
+        .options
+        .iter()
+        .map(|option| (option.name.as_str(), option.tally.as_f64()))
+        .collect();
+    options.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    options
+}
+
+/// Whole numbers without decimals, fractional points (of Dowdall polls) with two
+fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 {
+        format!("{}", value)
+    } else {
+        format!("{:.2}", value)
+    }
+}
+
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+fn shorten(name: &str) -> String {
+    if name.chars().count() <= MAX_LABEL_CHARS {
+        return name.to_string();
+    }
+    let mut short: String = name.chars().take(MAX_LABEL_CHARS - 1).collect();
+    short.push('…');
+    short
+}
+
+/// Escapes text for use in SVG text and attributes
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_charts() {
+    use crate::poll::PortableOption;
+
+    let state = PortableState {
+        options: vec![
+            PortableOption {
+                name: "<script>".into(),
+                tally: 1u64.into(),
+            },
+            PortableOption {
+                name: "b".repeat(40),
+                tally: 3u64.into(),
+            },
+        ],
+        points: None,
+    };
+    for svg in [
+        bar("Poll & co", &state),
+        pie("Poll & co", &state),
+        stacked(
+            "Poll & co",
+            &state,
+            &[Ballot(vec![0, 1]), Ballot(vec![1, 0])],
+        ),
+    ] {
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>"));
+        assert!(svg.contains("Poll &amp; co"));
+        assert!(svg.contains("&lt;script&gt;"));
+        assert!(!svg.contains("<script>"));
+    }
+    assert!(bar("", &state).contains(&format!("{}…", "b".repeat(23))));
+    // A single option with all the votes is a full circle
+    let single = PortableState {
+        options: vec![PortableOption {
+            name: "a".into(),
+            tally: 2u64.into(),
+        }],
+        points: None,
+    };
+    assert!(pie("", &single).contains("<circle"));
+    assert_eq!(ordinal(1), "1st");
+    assert_eq!(ordinal(12), "12th");
+    assert_eq!(ordinal(23), "23rd");
+}
@@ -0,0 +1,264 @@
+use anyhow::Context;
+use clap::{CommandFactory, Parser, Subcommand};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::db;
+use crate::export::{Export, ExportedPoll};
+use crate::poll::PollID;
+
+/// Pollinator 3000 poll server
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Path to a TOML configuration file
+    #[arg(long, short, global = true)]
+    pub config: Option<PathBuf>,
+    /// The database path (overrides the configuration)
+    #[arg(long = "database", short, global = true)]
+    pub db: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Running without a command starts the server
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+impl Args {
+    /// Parses the command line, exiting with a usage error if it's invalid
+    pub fn parse_checked() -> Self {
+        let args = Args::parse();
+        if let Err(err) = args.check() {
+            err.exit();
+        }
+        args
+    }
+
+    /// The server's positional arguments can't be used with a command, they would be ignored.
+    /// (clap's args_conflicts_with_subcommands would also refuse the global options)
+    fn check(&self) -> Result<(), clap::Error> {
+        if self.command.is_some()
+            && (self.serve.database.is_some() || self.serve.bind_address.is_some())
+        {
+            return Err(Args::command().error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "the database and bind address arguments can't be used with a command, use --database instead",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(clap::Args)]
+pub struct ServeArgs {
+    /// The database path (overrides the configuration)
+    pub database: Option<PathBuf>,
+    /// The address the server binds to (overrides the configuration)
+    pub bind_address: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the server (the default)
+    Serve(ServeArgs),
+    /// Validate the configuration and exit
+    CheckConfig,
+    /// Create a new database
+    InitDb,
+    /// Apply database schema changes from a newer version of the server
+    Migrate,
+    /// List all polls
+    ListPolls,
+    /// Show a poll's details
+    ShowPoll { id: String },
+    /// Delete a poll
+    DeletePoll { id: String },
+    /// Delete all polls
+    Purge {
+        /// Confirm deleting all polls
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Export polls to a JSON file
+    Export {
+        /// Export only this poll
+        #[arg(long)]
+        poll: Option<String>,
+        /// The file to write to (standard output by default)
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Import polls from a JSON file created by `export`. The polls get new ids.
+    Import { input: PathBuf },
+    /// Save a snapshot of the database
+    Backup {
+        /// The file to write to (a new file in the backup directory by default)
+        output: Option<PathBuf>,
+    },
+    /// Replace the contents of the database with a backup
+    Restore {
+        input: PathBuf,
+        /// Confirm replacing all polls
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+/// Runs a command managing the instance. The server doesn't have to be stopped.
+pub async fn run(command: Command, config: &Config) -> anyhow::Result<()> {
+    if let Command::InitDb = command {
+        db::init(&config.database).await?;
+        println!("Created database {:?}.", config.database);
+        return Ok(());
+    }
+
+    let pool = db::connect(&config.database)?;
+    // Polls can't be read from a database with an older schema. Backups and restoring
+    // copy the whole database, so they work with any version.
+    if !matches!(
+        command,
+        Command::Migrate | Command::Backup { .. } | Command::Restore { .. }
+    ) {
+        let version = db::schema_version(&pool).await?;
+        if version < db::SCHEMA_VERSION {
+            anyhow::bail!(
+                "The database schema is out of date (version {}, current {}), run `pollinator migrate` first.",
+                version,
+                db::SCHEMA_VERSION
+            );
+        }
+    }
+    match command {
+        Command::Migrate => {
+            let applied = db::migrate(&pool).await?;
+            println!("Applied {} migrations.", applied);
+        }
+        Command::ListPolls => {
+            for poll in db::list_polls(&pool).await? {
+                println!(
+                    "{}\t{}\t{}\t{} voters\t{}",
+                    poll.id, poll.poll_type, poll.date_created, poll.voters, poll.name
+                );
+            }
+        }
+        Command::ShowPoll { id } => {
+            let poll = db::get_poll(&pool, PollID::try_from(id.as_str())?).await?;
+            println!("ID: {}", poll.data.id);
+            println!("Name: {}", poll.data.name);
+            println!("Type: {}", poll.data.ptype);
+            println!("Date created: {}", poll.data.date_created.to_rfc3339());
+            println!("Voters: {}", poll.data.voters);
+            println!("Duplicate vote detection: {}", poll.data.settings.dedupe);
+            println!("Admin token: {}", poll.data.admin_link);
+        }
+        Command::DeletePoll { id } => {
+            let id = PollID::try_from(id.as_str())?;
+            // Make sure the randpart matches before deleting
+            db::get_poll(&pool, id).await?;
+            db::delete_poll(&pool, id).await?;
+            println!("Deleted poll {}.", id);
+        }
+        Command::Purge { yes } => {
+            if !yes {
+                anyhow::bail!("This deletes all polls, run with --yes to confirm.");
+            }
+            let deleted = db::purge(&pool).await?;
+            println!("Deleted {} polls.", deleted);
+        }
+        Command::Export { poll, output } => {
+            let polls = match poll {
+                Some(id) => vec![db::get_poll(&pool, PollID::try_from(id.as_str())?).await?],
+                None => db::all_polls(&pool).await?,
+            };
+            let mut exported = Vec::new();
+            for poll in &polls {
+                let ballots = db::get_ballots(&pool, poll.data.id).await?;
+                exported.push(ExportedPoll::new(poll, ballots));
+            }
+            let json = serde_json::to_string_pretty(&Export::new(exported))?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, json)
+                        .with_context(|| format!("Failed to write {:?}", path))?;
+                    eprintln!("Exported {} polls to {:?}.", polls.len(), path);
+                }
+                None => writeln!(std::io::stdout(), "{}", json)?,
+            }
+        }
+        Command::Import { input } => {
+            let json = std::fs::read_to_string(&input)
+                .with_context(|| format!("Failed to read {:?}", input))?;
+            let export = Export::from_json(&json)?;
+            // Decode every poll before inserting any, and insert them in a single
+            // transaction, so that a broken file imports nothing
+            let mut old_ids = Vec::new();
+            let mut polls = Vec::new();
+            for poll in export.polls {
+                let old_id = poll.id.clone();
+                // The indexes are assigned by the database when inserting
+                let poll = poll
+                    .into_poll(PollID::generate(0))
+                    .with_context(|| format!("Failed to import poll {}", old_id))?;
+                old_ids.push(old_id);
+                polls.push(poll);
+            }
+            let ids = db::import_polls(&pool, &polls).await?;
+            for (old_id, id) in old_ids.iter().zip(ids) {
+                println!("{} -> {}", old_id, id);
+            }
+        }
+        Command::Backup { output } => {
+            let path = match (output, config.backups()) {
+                (Some(path), _) => {
+                    if path.exists() {
+                        anyhow::bail!("File {:?} already exists.", path);
+                    }
+                    db::backup(&pool, &path).await?;
+                    path
+                }
+                (None, Some(backups)) => backups.create(&pool).await?,
+                (None, None) => {
+                    anyhow::bail!("No backup directory configured, specify the output file.")
+                }
+            };
+            println!("Backup saved to {:?}.", path);
+        }
+        Command::Restore { input, yes } => {
+            if !yes {
+                anyhow::bail!("This replaces all polls, run with --yes to confirm.");
+            }
+            let polls = db::restore(&pool, &input).await?;
+            println!("Restored {} polls from {:?}.", polls, input);
+        }
+        Command::Serve(_) | Command::CheckConfig | Command::InitDb => unreachable!(),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_parse_args() {
+    let args = Args::try_parse_from(["pollinator", "t.db", "0.0.0.0:8080"]).unwrap();
+    assert!(args.command.is_none());
+    assert!(args.check().is_ok());
+    assert_eq!(args.serve.database, Some(PathBuf::from("t.db")));
+    assert_eq!(args.serve.bind_address.as_deref(), Some("0.0.0.0:8080"));
+
+    let args = Args::try_parse_from(["pollinator", "-d", "t.db", "list-polls"]).unwrap();
+    assert!(matches!(args.command, Some(Command::ListPolls)));
+    assert_eq!(args.db, Some(PathBuf::from("t.db")));
+    let args = Args::try_parse_from(["pollinator", "list-polls", "--database", "t.db"]).unwrap();
+    assert_eq!(args.db, Some(PathBuf::from("t.db")));
+
+    // The server's positional arguments would be ignored by other commands
+    for command in [
+        &["pollinator", "t.db", "list-polls"][..],
+        &["pollinator", "t.db", "serve"],
+        &["pollinator", "t.db", "0.0.0.0:8080", "migrate"],
+    ] {
+        let args = Args::try_parse_from(command).unwrap();
+        assert!(args.check().is_err());
+    }
+    let args = Args::try_parse_from(["pollinator", "serve", "t.db"]).unwrap();
+    assert!(args.check().is_ok());
+}
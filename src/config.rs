@@ -0,0 +1,475 @@
+use anyhow::Context;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::backup::Backups;
+use crate::logging::LogFormat;
+use crate::metrics::MetricsAccess;
+use crate::pow::PowConfig;
+use crate::rate::{self, Policy, Route, RoutePolicy};
+use crate::security::EmbedPolicy;
+use crate::tls::CertStore;
+use crate::util;
+use crate::webhook::Webhooks;
+
+/// Rate limits of each route, see rate::Policy.
+/// By default a single IP has to wait 10 minutes before creating a new poll.
+const CREATE_LIMIT: RoutePolicy = RoutePolicy {
+    client: Some(Policy::new(Duration::from_secs(10 * 60), 1)),
+    global: None,
+};
+/// By default a single IP has to wait 30 minutes before voting on a single poll again.
+const VOTE_LIMIT: RoutePolicy = RoutePolicy {
+    client: Some(Policy::new(Duration::from_secs(30 * 60), 1)),
+    global: None,
+};
+const RESULTS_LIMIT: RoutePolicy = RoutePolicy {
+    client: Some(Policy::new(Duration::from_secs(1), 10)),
+    global: None,
+};
+/// Slows down guessing admin tokens. There's no global limit by default, since anyone
+/// could use it up and lock the admins out.
+const ADMIN_LOGIN_LIMIT: RoutePolicy = RoutePolicy {
+    client: Some(Policy::new(Duration::from_secs(60), 5)),
+    global: None,
+};
+/// The prefix of bind addresses that are Unix socket paths
+pub const UNIX_PREFIX: &str = "unix:";
+/// The number of bits the proof of work difficulty can rise by under heavy traffic,
+/// unless the maximum difficulty is set.
+const POW_MAX_EXTRA_DIFFICULTY: u8 = 6;
+
+/// Server configuration. Read from a TOML file (see `--config`), where every option is optional.
+/// Every option can be overridden by a "POLL_*" environmental variable, see Config::load.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The database path
+    pub database: PathBuf,
+    /// The addresses the server binds to, "unix:{path}" for Unix sockets
+    pub bind: Vec<String>,
+    /// The directory served under /static
+    pub static_dir: PathBuf,
+    /// A "password" for the website administrator, enables the administration webpage
+    pub admin_token: Option<String>,
+    /// The secret used to sign cookies, see session::SessionKey
+    pub session_key: Option<String>,
+    /// The time after which an admin session expires (in seconds)
+    #[serde(deserialize_with = "seconds")]
+    pub session_length: Duration,
+    /// The time interval between each rate limit store cleanup (in seconds)
+    #[serde(deserialize_with = "seconds")]
+    pub cleanup_interval: Duration,
+    /// Save rate limits to the database, so that they survive restarts
+    pub persist_limits: bool,
+    /// The prefix length of IPv4 networks that share rate limits
+    pub ipv4_prefix: u8,
+    /// The prefix length of IPv6 networks that share rate limits
+    pub ipv6_prefix: u8,
+    /// Addresses or CIDR ranges of reverse proxies allowed to report client addresses
+    pub trusted_proxies: Vec<String>,
+    /// The header the trusted proxies report client addresses in
+    pub proxy_header: String,
+    /// Websites allowed to embed polls in frames (see /embed), as CSP sources
+    pub embed_ancestors: Vec<String>,
+    /// The format of log lines, the level is set with RUST_LOG
+    pub log_format: LogFormat,
+    pub limits: LimitsConfig,
+    pub pow: PowSettings,
+    pub backup: BackupSettings,
+    pub webhooks: WebhookSettings,
+    pub metrics: MetricsSettings,
+    pub tls: TlsSettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            database: PathBuf::from("db/main.db"),
+            bind: vec!["0.0.0.0:8080".to_string()],
+            static_dir: PathBuf::from("static/"),
+            admin_token: None,
+            session_key: None,
+            session_length: Duration::from_secs(60 * 60),
+            cleanup_interval: Duration::from_secs(30),
+            persist_limits: false,
+            ipv4_prefix: 32,
+            // A single client can usually use a whole /64 network
+            ipv6_prefix: 64,
+            trusted_proxies: Vec::new(),
+            proxy_header: "X-Forwarded-For".to_string(),
+            embed_ancestors: Vec::new(),
+            log_format: LogFormat::Text,
+            limits: LimitsConfig::default(),
+            pow: PowSettings::default(),
+            backup: BackupSettings::default(),
+            webhooks: WebhookSettings::default(),
+            metrics: MetricsSettings::default(),
+            tls: TlsSettings::default(),
+        }
+    }
+}
+
+/// Rate limits set in the configuration file, on top of the defaults of each route
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub create: RouteLimits,
+    pub vote: RouteLimits,
+    pub results: RouteLimits,
+    pub admin_login: RouteLimits,
+}
+
+/// The same options as the "POLL_{ROUTE}_LIMIT", "POLL_{ROUTE}_BURST",
+/// "POLL_{ROUTE}_GLOBAL_LIMIT" and "POLL_{ROUTE}_GLOBAL_BURST" environmental variables
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLimits {
+    pub limit: Option<u64>,
+    pub burst: Option<u32>,
+    pub global_limit: Option<u64>,
+    pub global_burst: Option<u32>,
+}
+
+impl RouteLimits {
+    /// Applies the limits to the default policy of a route
+    fn apply(&self, default: RoutePolicy) -> RoutePolicy {
+        fn policy(
+            period: Option<u64>,
+            burst: Option<u32>,
+            default: Option<Policy>,
+        ) -> Option<Policy> {
+            let default = default.unwrap_or(Policy::new(Duration::ZERO, 1));
+            let period = period.map(Duration::from_secs).unwrap_or(default.period);
+            let burst = burst.unwrap_or(default.burst);
+            (!period.is_zero() && burst > 0).then(|| Policy::new(period, burst))
+        }
+        RoutePolicy {
+            client: policy(self.limit, self.burst, default.client),
+            global: policy(self.global_limit, self.global_burst, default.global),
+        }
+    }
+}
+
+/// Proof of work options, see pow::PowConfig
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowSettings {
+    pub difficulty: u8,
+    /// By default a few bits above the difficulty
+    pub max_difficulty: Option<u8>,
+    pub threshold: u32,
+}
+
+impl Default for PowSettings {
+    fn default() -> Self {
+        PowSettings {
+            difficulty: 0,
+            max_difficulty: None,
+            threshold: 30,
+        }
+    }
+}
+
+/// Database backups, see backup::Backups
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupSettings {
+    /// The directory backups are saved to. Backups are off if it's not set.
+    pub dir: Option<PathBuf>,
+    /// The time between scheduled backups (in seconds), 0 turns them off
+    #[serde(deserialize_with = "seconds")]
+    pub interval: Duration,
+    /// The number of backups kept, 0 keeps all of them
+    pub keep: usize,
+}
+
+impl Default for BackupSettings {
+    fn default() -> Self {
+        BackupSettings {
+            dir: None,
+            interval: Duration::from_secs(24 * 60 * 60),
+            keep: 7,
+        }
+    }
+}
+
+/// Webhooks of polls, see webhook::Webhooks
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSettings {
+    /// Whether poll admins can register webhooks. Off by default, since the server
+    /// then sends requests to URLs chosen by anyone who creates a poll.
+    pub enabled: bool,
+    /// Allow webhooks to loopback and private network addresses, e.g. for testing
+    pub allow_private: bool,
+}
+
+/// The Prometheus metrics endpoint, see metrics::MetricsAccess
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSettings {
+    /// Whether /metrics is served. Off by default, since it shows the server's traffic.
+    pub enabled: bool,
+    /// A token scrapers have to send in an "Authorization: Bearer" header
+    pub token: Option<String>,
+}
+
+/// Serving HTTPS directly, see tls::CertStore
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    /// The addresses the server accepts HTTPS connections on, in addition to `bind`
+    pub bind: Vec<String>,
+    /// The PEM file with the certificate chain
+    pub cert: Option<PathBuf>,
+    /// The PEM file with the certificate's private key
+    pub key: Option<PathBuf>,
+}
+
+/// Deserializes a Duration from a number of seconds
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+/// Splits a comma-separated list
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl Config {
+    /// Reads the configuration file (or uses the defaults if there is none)
+    /// and applies the environmental variables on top of it.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => {
+                let file = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read the config file {:?}", path))?;
+                toml::from_str(&file).with_context(|| format!("Invalid config file {:?}", path))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        if let Ok(database) = std::env::var("POLL_DATABASE") {
+            self.database = database.into();
+        }
+        if let Ok(bind) = std::env::var("POLL_BIND") {
+            self.bind = list(&bind);
+        }
+        if let Ok(static_dir) = std::env::var("POLL_STATIC_DIR") {
+            self.static_dir = static_dir.into();
+        }
+        if let Ok(admin_token) = std::env::var("POLL_ADMIN_TOKEN") {
+            self.admin_token = Some(admin_token);
+        }
+        if let Ok(session_key) = std::env::var("POLL_SESSION_KEY") {
+            self.session_key = Some(session_key);
+        }
+        self.session_length =
+            util::get_env_duration_or("POLL_SESSION_LENGTH", self.session_length)?;
+        self.cleanup_interval =
+            util::get_env_duration_or("POLL_CLEANUP_INTERVAL", self.cleanup_interval)?;
+        self.persist_limits = util::get_env_flag_or("POLL_PERSIST_LIMITS", self.persist_limits);
+        self.ipv4_prefix = util::get_env_number_or("POLL_IPV4_PREFIX", self.ipv4_prefix)?;
+        self.ipv6_prefix = util::get_env_number_or("POLL_IPV6_PREFIX", self.ipv6_prefix)?;
+        if let Ok(proxies) = std::env::var("POLL_TRUSTED_PROXIES") {
+            self.trusted_proxies = list(&proxies);
+        }
+        if let Ok(header) = std::env::var("POLL_PROXY_HEADER") {
+            self.proxy_header = header;
+        }
+        if let Ok(ancestors) = std::env::var("POLL_EMBED_ANCESTORS") {
+            self.embed_ancestors = list(&ancestors);
+        }
+        if let Ok(format) = std::env::var("POLL_LOG_FORMAT") {
+            self.log_format = LogFormat::try_parse(&format)
+                .context("POLL_LOG_FORMAT, must be \"text\" or \"json\"")?;
+        }
+        self.pow.difficulty = util::get_env_number_or("POLL_POW_DIFFICULTY", self.pow.difficulty)?;
+        if let Ok(max) = std::env::var("POLL_POW_MAX_DIFFICULTY") {
+            self.pow.max_difficulty = Some(
+                max.parse()
+                    .context("POLL_POW_MAX_DIFFICULTY, must be a number")?,
+            );
+        }
+        self.pow.threshold = util::get_env_number_or("POLL_POW_THRESHOLD", self.pow.threshold)?;
+        if let Ok(dir) = std::env::var("POLL_BACKUP_DIR") {
+            self.backup.dir = Some(dir.into());
+        }
+        self.backup.interval =
+            util::get_env_duration_or("POLL_BACKUP_INTERVAL", self.backup.interval)?;
+        self.backup.keep = util::get_env_number_or("POLL_BACKUP_KEEP", self.backup.keep)?;
+        if let Ok(bind) = std::env::var("POLL_TLS_BIND") {
+            self.tls.bind = list(&bind);
+        }
+        if let Ok(cert) = std::env::var("POLL_TLS_CERT") {
+            self.tls.cert = Some(cert.into());
+        }
+        if let Ok(key) = std::env::var("POLL_TLS_KEY") {
+            self.tls.key = Some(key.into());
+        }
+        self.webhooks.enabled = util::get_env_flag_or("POLL_WEBHOOKS", self.webhooks.enabled);
+        self.webhooks.allow_private =
+            util::get_env_flag_or("POLL_WEBHOOK_ALLOW_PRIVATE", self.webhooks.allow_private);
+        self.metrics.enabled = util::get_env_flag_or("POLL_METRICS", self.metrics.enabled);
+        if let Ok(token) = std::env::var("POLL_METRICS_TOKEN") {
+            self.metrics.token = Some(token);
+        }
+        Ok(())
+    }
+
+    /// The rate limits of every route: the defaults, with the configuration file
+    /// and the environmental variables applied on top
+    pub fn policies(&self) -> anyhow::Result<HashMap<Route, RoutePolicy>> {
+        let mut policies = HashMap::new();
+        for (route, name, limits, default) in [
+            (Route::Create, "CREATE", &self.limits.create, CREATE_LIMIT),
+            (Route::Vote, "VOTE", &self.limits.vote, VOTE_LIMIT),
+            (
+                Route::Results,
+                "RESULTS",
+                &self.limits.results,
+                RESULTS_LIMIT,
+            ),
+            (
+                Route::AdminLogin,
+                "ADMIN_LOGIN",
+                &self.limits.admin_login,
+                ADMIN_LOGIN_LIMIT,
+            ),
+        ] {
+            policies.insert(
+                route,
+                RoutePolicy::from_env_or(name, limits.apply(default))?,
+            );
+        }
+        Ok(policies)
+    }
+
+    pub fn trusted_proxies(&self) -> anyhow::Result<rate::TrustedProxies> {
+        rate::TrustedProxies::parse(&self.trusted_proxies.join(","), &self.proxy_header)
+            .context("trusted_proxies or proxy_header is invalid")
+    }
+
+    pub fn pow(&self) -> PowConfig {
+        PowConfig {
+            difficulty: self.pow.difficulty,
+            max_difficulty: self
+                .pow
+                .max_difficulty
+                .unwrap_or(self.pow.difficulty.saturating_add(POW_MAX_EXTRA_DIFFICULTY)),
+            threshold: self.pow.threshold,
+        }
+    }
+
+    pub fn embed_policy(&self) -> anyhow::Result<EmbedPolicy> {
+        EmbedPolicy::new(&self.embed_ancestors).context("embed_ancestors is invalid")
+    }
+
+    /// Returns None if backups are off
+    pub fn backups(&self) -> Option<Backups> {
+        self.backup
+            .dir
+            .clone()
+            .map(|dir| Backups::new(dir, self.backup.keep))
+    }
+
+    /// Returns None if HTTPS is off
+    pub fn cert_store(&self) -> anyhow::Result<Option<CertStore>> {
+        if self.tls.bind.is_empty() {
+            return Ok(None);
+        }
+        match (&self.tls.cert, &self.tls.key) {
+            (Some(cert), Some(key)) => Ok(Some(CertStore::new(cert, key)?)),
+            _ => anyhow::bail!("tls.cert and tls.key have to be set to serve HTTPS."),
+        }
+    }
+
+    /// Returns None if webhooks are off
+    pub fn webhooks(&self) -> Option<Webhooks> {
+        self.webhooks
+            .enabled
+            .then(|| Webhooks::new(self.webhooks.allow_private))
+    }
+
+    /// Returns None if the metrics endpoint is off
+    pub fn metrics_access(&self) -> Option<MetricsAccess> {
+        self.metrics
+            .enabled
+            .then(|| MetricsAccess::new(self.metrics.token.clone()))
+    }
+
+    /// Checks everything that can be checked without starting the server
+    pub fn check(&self) -> anyhow::Result<()> {
+        if !self.database.exists() {
+            anyhow::bail!(
+                "Database file {:?} does not exist or could not be read.",
+                self.database
+            );
+        }
+        if !self.static_dir.is_dir() {
+            anyhow::bail!("Static directory {:?} does not exist.", self.static_dir);
+        }
+        if self.bind.is_empty() && self.tls.bind.is_empty() {
+            anyhow::bail!("No bind addresses set.");
+        }
+        for address in &self.bind {
+            if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+                let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+                if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                    anyhow::bail!("Directory of the Unix socket {:?} does not exist.", path);
+                }
+                continue;
+            }
+            std::net::ToSocketAddrs::to_socket_addrs(address.as_str())
+                .with_context(|| format!("Invalid bind address: {}", address))?;
+        }
+        for address in &self.tls.bind {
+            std::net::ToSocketAddrs::to_socket_addrs(address.as_str())
+                .with_context(|| format!("Invalid TLS bind address: {}", address))?;
+        }
+        self.cert_store()?;
+        rate::LimitStore::new(self.policies()?, self.ipv4_prefix, self.ipv6_prefix)?;
+        self.trusted_proxies()?;
+        self.embed_policy()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_config_file() {
+    let config: Config = toml::from_str(
+        r#"
+        bind = ["127.0.0.1:8080", "[::1]:8080"]
+        persist_limits = true
+
+        [limits.vote]
+        burst = 3
+
+        [limits.results]
+        limit = 0
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.bind.len(), 2);
+    assert!(config.persist_limits);
+    assert_eq!(config.ipv4_prefix, 32);
+
+    let vote = config.limits.vote.apply(VOTE_LIMIT).client.unwrap();
+    assert_eq!(vote.period, VOTE_LIMIT.client.unwrap().period);
+    assert_eq!(vote.burst, 3);
+    assert!(config.limits.results.apply(RESULTS_LIMIT).client.is_none());
+
+    assert!(toml::from_str::<Config>("unknown_option = 1").is_err());
+}
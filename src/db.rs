@@ -1,12 +1,72 @@
+use anyhow::Context;
+use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::types::Type;
+use std::path::Path;
 use thiserror::Error;
 
 use crate::{
-    poll::{create_poll_format_from_bytes, Poll, PollData, PollID, PollType},
+    poll::{create_poll_format_from_bytes, Ballot, Poll, PollData, PollID, PollSettings, PollType},
+    rate::{LimitRecord, Route},
+    templates::AuditEntry,
     util,
+    webhook::{Delivery, Webhook, DELIVERY_LOG_LENGTH},
 };
 
-pub type DbPool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
+pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+
+/// Schema changes applied on top of db/db.sql, in order.
+/// The number of applied migrations is stored in the database's `user_version`.
+const MIGRATIONS: &[&str] = &[
+    // 1: Rate limits persisted across restarts
+    "CREATE TABLE rate_limits (
+        route TEXT NOT NULL,
+        addr TEXT,
+        poll INTEGER,
+        expires INTEGER NOT NULL
+    );",
+    // 2: Per-poll duplicate vote detection, see PollSettings
+    "ALTER TABLE polls ADD COLUMN dedupe INTEGER NOT NULL DEFAULT 0;",
+    // 3: Ballots of ranked polls, see poll::Ballot
+    "CREATE TABLE ballots (
+        poll INTEGER NOT NULL,
+        ranks TEXT NOT NULL
+    );
+    CREATE INDEX ballots_poll ON ballots (poll);",
+    // 4: Changes made by poll admins, see templates::AuditEntry
+    "CREATE TABLE audit_log (
+        poll INTEGER NOT NULL,
+        time TEXT NOT NULL,
+        action TEXT NOT NULL,
+        details TEXT NOT NULL
+    );
+    CREATE INDEX audit_log_poll ON audit_log (poll);",
+    // 5: Webhooks registered by poll admins and their deliveries, see webhook::Webhooks
+    "CREATE TABLE webhooks (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        poll INTEGER NOT NULL,
+        url TEXT NOT NULL,
+        secret TEXT NOT NULL
+    );
+    CREATE INDEX webhooks_poll ON webhooks (poll);
+    CREATE TABLE webhook_deliveries (
+        poll INTEGER NOT NULL,
+        webhook INTEGER NOT NULL,
+        url TEXT NOT NULL,
+        time TEXT NOT NULL,
+        event TEXT NOT NULL,
+        attempt INTEGER NOT NULL,
+        result TEXT NOT NULL
+    );
+    CREATE INDEX webhook_deliveries_poll ON webhook_deliveries (poll);",
+    // 6: Values generated by the server that have to survive restarts, see session_key
+    "CREATE TABLE settings (
+        name TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );",
+];
+
+/// The schema version of a fully migrated database
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -24,6 +84,8 @@ pub enum Error {
     Connection(r2d2::Error),
     #[error("Failed serializing poll data: {0:?}")]
     SerializationError(anyhow::Error),
+    #[error("Database schema version {0} is newer than this server supports")]
+    UnknownSchemaVersion(usize),
 }
 
 impl actix_web::error::ResponseError for Error {
@@ -35,6 +97,223 @@ impl actix_web::error::ResponseError for Error {
     }
 }
 
+/// Opens an existing database
+pub fn connect(path: &Path) -> anyhow::Result<DbPool> {
+    connect_with(path, r2d2::NopEventHandler)
+}
+
+/// Opens an existing database, reporting connection pool events to the handler
+pub fn connect_with(
+    path: &Path,
+    events: impl r2d2::HandleEvent + 'static,
+) -> anyhow::Result<DbPool> {
+    if !path.exists() {
+        anyhow::bail!(
+            "Database file {:?} does not exist or could not be read.",
+            path
+        );
+    }
+    Ok(DbPool::builder()
+        .event_handler(Box::new(events))
+        .build(SqliteConnectionManager::file(path))?)
+}
+
+/// Creates a new database from the db/db.sql template, with all migrations applied
+pub async fn init(path: &Path) -> anyhow::Result<DbPool> {
+    if path.exists() {
+        anyhow::bail!("Database file {:?} already exists.", path);
+    }
+    let pool = DbPool::new(SqliteConnectionManager::file(path))?;
+    pool.get()?
+        .execute_batch(include_str!("../db/db.sql"))
+        .map_err(Error::Query)?;
+    migrate(&pool).await?;
+    Ok(pool)
+}
+
+/// Returns the number of migrations applied to the database
+pub async fn schema_version(pool: &DbPool) -> Result<usize, Error> {
+    let version: usize = pool
+        .get()
+        .map_err(Error::Connection)?
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(Error::Query)?;
+    if version > MIGRATIONS.len() {
+        return Err(Error::UnknownSchemaVersion(version));
+    }
+    Ok(version)
+}
+
+/// Checks that a connection can be made within the timeout and returns the schema version
+pub async fn ping(pool: &DbPool, timeout: std::time::Duration) -> Result<usize, Error> {
+    pool.get_timeout(timeout)
+        .map_err(Error::Connection)?
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(Error::Query)
+}
+
+/// Applies all migrations that haven't been applied to the database yet.
+/// Returns the number of migrations applied.
+pub async fn migrate(pool: &DbPool) -> Result<usize, Error> {
+    let version = schema_version(pool).await?;
+    let mut conn = pool.get().map_err(Error::Connection)?;
+
+    let tx = conn.transaction().map_err(Error::Database)?;
+    for migration in &MIGRATIONS[version..] {
+        tx.execute_batch(migration).map_err(Error::Query)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())
+        .map_err(Error::Query)?;
+    tx.commit().map_err(Error::Database)?;
+
+    Ok(MIGRATIONS.len() - version)
+}
+
+/// Copies the database to a new file using SQLite's backup API.
+/// The copy is consistent even if the database is written to while it's made.
+pub async fn backup(pool: &DbPool, path: &Path) -> Result<(), Error> {
+    pool.get()
+        .map_err(Error::Connection)?
+        .backup(rusqlite::DatabaseName::Main, path, None)
+        .map_err(Error::Database)
+}
+
+/// Checks that a backup can be restored: its schema isn't newer than this server's
+/// and the data of every poll can be read. Returns the number of polls in the backup.
+/// Backups made by older versions are checked after migrating a copy in memory.
+pub async fn validate_backup(path: &Path) -> anyhow::Result<usize> {
+    // A single connection, since every in-memory connection has its own database
+    let backup = DbPool::builder()
+        .max_size(1)
+        .build(SqliteConnectionManager::memory())?;
+    backup
+        .get()?
+        .restore(rusqlite::DatabaseName::Main, path, None::<fn(_)>)
+        .map_err(Error::Database)?;
+    schema_version(&backup).await?;
+    migrate(&backup).await?;
+    let polls = all_polls(&backup)
+        .await
+        .context("The backup contains invalid polls")?;
+    Ok(polls.len())
+}
+
+/// Replaces the contents of the database with a backup, after validating it.
+/// Works while the server is running. Returns the number of restored polls.
+pub async fn restore(pool: &DbPool, path: &Path) -> anyhow::Result<usize> {
+    if !path.exists() {
+        anyhow::bail!(
+            "Backup file {:?} does not exist or could not be read.",
+            path
+        );
+    }
+    let polls = validate_backup(path).await?;
+    pool.get()?
+        .restore(rusqlite::DatabaseName::Main, path, None::<fn(_)>)
+        .map_err(Error::Database)?;
+    // Backups made by older versions of the server
+    migrate(pool).await?;
+    Ok(polls)
+}
+
+/// Moves everything from the write-ahead log (if the database uses one) into the database
+/// file and truncates the log, so that the file is complete on its own
+pub async fn checkpoint(pool: &DbPool) -> Result<(), Error> {
+    pool.get()
+        .map_err(Error::Connection)?
+        .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+        .map_err(Error::Query)
+}
+
+/// Replaces all stored rate limits
+pub async fn save_limits(pool: &DbPool, limits: &[LimitRecord]) -> Result<(), Error> {
+    let mut conn = pool.get().map_err(Error::Connection)?;
+
+    let tx = conn.transaction().map_err(Error::Database)?;
+    tx.execute("DELETE FROM rate_limits", [])
+        .map_err(Error::Query)?;
+    {
+        let mut insert = tx
+            .prepare("INSERT INTO rate_limits (route, addr, poll, expires) VALUES (?1, ?2, ?3, ?4)")
+            .map_err(Error::Query)?;
+        for limit in limits {
+            insert
+                .execute(rusqlite::params![
+                    limit.route.to_string(),
+                    limit.addr.map(|a| a.to_string()),
+                    limit.poll,
+                    limit.expires.timestamp_millis(),
+                ])
+                .map_err(Error::Insert)?;
+        }
+    }
+    tx.commit().map_err(Error::Database)
+}
+
+/// Retrieves all stored rate limits
+pub async fn load_limits(pool: &DbPool) -> Result<Vec<LimitRecord>, Error> {
+    let conn = pool.get().map_err(Error::Connection)?;
+
+    let mut query = conn
+        .prepare("SELECT route, addr, poll, expires FROM rate_limits")
+        .map_err(Error::Query)?;
+
+    let limit_iter = query
+        .query_map([], |row| {
+            let route: String = row.get(0)?;
+            let route = Route::try_parse(&route).ok_or_else(|| {
+                rusqlite::Error::FromSqlConversionFailure(0, Type::Text, route.into())
+            })?;
+            let addr = row
+                .get::<_, Option<String>>(1)?
+                .map(|a| a.parse())
+                .transpose()
+                .map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(1, Type::Text, Box::new(e))
+                })?;
+            let expires = chrono::TimeZone::timestamp_millis_opt(&chrono::Utc, row.get(3)?)
+                .single()
+                .ok_or(rusqlite::Error::IntegralValueOutOfRange(3, 0))?;
+            Ok(LimitRecord {
+                route,
+                addr,
+                poll: row.get(2)?,
+                expires,
+            })
+        })
+        .map_err(Error::Query)?;
+
+    let limits: Result<Vec<LimitRecord>, rusqlite::Error> = limit_iter.collect();
+
+    limits.map_err(Error::Database)
+}
+
+/// Reads a poll from a row of the polls table
+fn poll_from_row(row: &rusqlite::Row) -> rusqlite::Result<Poll> {
+    let ptype = PollType::try_parse(&row.get::<_, String>(2)?)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, e.into()))?;
+    let randpart: String = row.get(1)?;
+    let id_randpart = util::read_base64_u64(&randpart)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, e.into()))?;
+    Ok(Poll {
+        data: PollData {
+            id: PollID::new(row.get(0)?, id_randpart),
+            ptype,
+            name: row.get(3)?,
+            date_created: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(4)?)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(3, Type::Text, e.into()))?
+                .into(),
+            admin_link: row.get(5)?,
+            voters: row.get(6)?,
+            settings: PollSettings {
+                dedupe: row.get(8)?,
+            },
+        },
+        format: create_poll_format_from_bytes(ptype, row.get(7)?)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(7, Type::Blob, e.into()))?,
+    })
+}
+
 /// Retrieves a single poll using it's unique id from the database
 pub async fn get_poll(pool: &DbPool, id: PollID) -> Result<Poll, Error> {
     let conn = pool.get().map_err(Error::Connection)?;
@@ -44,31 +323,7 @@ pub async fn get_poll(pool: &DbPool, id: PollID) -> Result<Poll, Error> {
         .map_err(Error::Query)?;
 
     let mut poll_iter = query
-        .query_map([id.index()], |row| {
-            let ptype = PollType::try_parse(&row.get::<_, String>(2)?)
-                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, e.into()))?;
-            let randpart: String = row.get(1)?;
-            let id_randpart = util::read_base64_u64(&randpart)
-                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, e.into()))?;
-            let read_id: PollID = PollID::new(id.0, id_randpart);
-            Ok(Poll {
-                data: PollData {
-                    id: read_id,
-                    ptype,
-                    name: row.get(3)?,
-                    date_created: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(4)?)
-                        .map_err(|e| {
-                            rusqlite::Error::FromSqlConversionFailure(3, Type::Text, e.into())
-                        })?
-                        .into(),
-                    admin_link: row.get(5)?,
-                    voters: row.get(6)?,
-                },
-                format: create_poll_format_from_bytes(ptype, row.get(7)?).map_err(|e| {
-                    rusqlite::Error::FromSqlConversionFailure(7, Type::Blob, e.into())
-                })?,
-            })
-        })
+        .query_map([id.index()], poll_from_row)
         .map_err(Error::Query)?;
 
     let poll = poll_iter
@@ -84,6 +339,22 @@ pub async fn get_poll(pool: &DbPool, id: PollID) -> Result<Poll, Error> {
     Ok(poll)
 }
 
+/// Retrieves *ALL POLLS* with their format data. Even slower than list_polls.
+pub async fn all_polls(pool: &DbPool) -> Result<Vec<Poll>, Error> {
+    let conn = pool.get().map_err(Error::Connection)?;
+
+    let mut query = conn
+        .prepare("SELECT * FROM polls ORDER BY id")
+        .map_err(Error::Query)?;
+
+    let polls: Result<Vec<Poll>, rusqlite::Error> = query
+        .query_map([], poll_from_row)
+        .map_err(Error::Query)?
+        .collect();
+
+    polls.map_err(Error::Database)
+}
+
 /// Retrieves the last ID assigned to a poll in the database
 /// Note: This does not find the last PollID (with random part) only the raw ID part
 pub async fn last_id(pool: &DbPool) -> Result<usize, Error> {
@@ -105,7 +376,41 @@ pub async fn last_id(pool: &DbPool) -> Result<usize, Error> {
 /// Inserts a poll into the database
 pub async fn insert_poll(pool: &DbPool, poll: Poll) -> Result<(), Error> {
     let conn = pool.get().map_err(Error::Connection)?;
+    insert_poll_row(&conn, &poll)?;
+    Ok(())
+}
 
+/// Inserts polls copied from another instance with their ballots, all of them or none.
+/// The polls get new indexes assigned by the database, their ids are returned in order.
+pub async fn import_polls(
+    pool: &DbPool,
+    polls: &[(Poll, Vec<Ballot>)],
+) -> Result<Vec<PollID>, Error> {
+    let mut conn = pool.get().map_err(Error::Connection)?;
+
+    let tx = conn.transaction().map_err(Error::Database)?;
+    let mut ids = Vec::with_capacity(polls.len());
+    {
+        let mut insert = tx
+            .prepare("INSERT INTO ballots (poll, ranks) VALUES (?1, ?2)")
+            .map_err(Error::Query)?;
+        for (poll, ballots) in polls {
+            let id = PollID::new(insert_poll_row(&tx, poll)?, poll.data.id.randpart());
+            for ballot in ballots {
+                insert
+                    .execute(rusqlite::params![id.index(), ballot.to_db()])
+                    .map_err(Error::Insert)?;
+            }
+            ids.push(id);
+        }
+    }
+    tx.commit().map_err(Error::Database)?;
+    Ok(ids)
+}
+
+/// Inserts a poll, returning the index the database assigned to it
+/// (the index in the poll's id is ignored)
+fn insert_poll_row(conn: &rusqlite::Connection, poll: &Poll) -> Result<u64, Error> {
     let params = rusqlite::params![
         util::encode_base64_u64(poll.data.id.randpart()),
         poll.data.ptype.to_string(),
@@ -116,14 +421,15 @@ pub async fn insert_poll(pool: &DbPool, poll: Poll) -> Result<(), Error> {
         poll.format
             .save_state()
             .map_err(Error::SerializationError)?,
+        poll.data.settings.dedupe,
     ];
 
     conn
-        .execute("INSERT INTO polls (randpart, type, name, date_created, admin_link, voters, format_data) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        .execute("INSERT INTO polls (randpart, type, name, date_created, admin_link, voters, format_data, dedupe) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
     params)
         .map_err(Error::Insert)?;
 
-    Ok(())
+    Ok(conn.last_insert_rowid() as u64)
 }
 
 /// Sets the number of voters and updates the format data
@@ -148,24 +454,366 @@ pub async fn update_poll(pool: &DbPool, poll: &Poll) -> Result<bool, Error> {
     .map(|u| u == 1)
 }
 
-/// Completely clears the polls table, returns number of deleted rows
-pub async fn purge(pool: &DbPool) -> Result<usize, Error> {
+/// Saves a poll after a vote was registered, along with the vote's ballot (if the poll's
+/// format stores them). Returns true if a poll was updated
+pub async fn record_vote(
+    pool: &DbPool,
+    poll: &Poll,
+    ballot: Option<&Ballot>,
+) -> Result<bool, Error> {
+    let mut conn = pool.get().map_err(Error::Connection)?;
+
+    let tx = conn.transaction().map_err(Error::Database)?;
+    let updated = tx
+        .execute(
+            "UPDATE polls SET voters = ?2, format_data = ?3 WHERE id = ?1",
+            rusqlite::params![
+                poll.data.id.index(),
+                poll.data.voters,
+                poll.format
+                    .save_state()
+                    .map_err(Error::SerializationError)?,
+            ],
+        )
+        .map_err(Error::Query)?;
+    if let Some(ballot) = ballot {
+        tx.execute(
+            "INSERT INTO ballots (poll, ranks) VALUES (?1, ?2)",
+            rusqlite::params![poll.data.id.index(), ballot.to_db()],
+        )
+        .map_err(Error::Insert)?;
+    }
+    tx.commit().map_err(Error::Database)?;
+
+    // id is unique, so the number of rows updated should be 0 or 1
+    Ok(updated == 1)
+}
+
+/// Saves a poll after importing ballots into it, along with the ballots (if the poll's
+/// format stores them) and a record in the audit log. Returns true if a poll was updated
+pub async fn record_import(
+    pool: &DbPool,
+    poll: &Poll,
+    ballots: &[Ballot],
+    entry: &AuditEntry,
+) -> Result<bool, Error> {
+    let mut conn = pool.get().map_err(Error::Connection)?;
+
+    let tx = conn.transaction().map_err(Error::Database)?;
+    let updated = tx
+        .execute(
+            "UPDATE polls SET voters = ?2, format_data = ?3 WHERE id = ?1",
+            rusqlite::params![
+                poll.data.id.index(),
+                poll.data.voters,
+                poll.format
+                    .save_state()
+                    .map_err(Error::SerializationError)?,
+            ],
+        )
+        .map_err(Error::Query)?;
+    {
+        let mut insert = tx
+            .prepare("INSERT INTO ballots (poll, ranks) VALUES (?1, ?2)")
+            .map_err(Error::Query)?;
+        for ballot in ballots {
+            insert
+                .execute(rusqlite::params![poll.data.id.index(), ballot.to_db()])
+                .map_err(Error::Insert)?;
+        }
+    }
+    insert_audit_entry(&tx, poll.data.id, entry)?;
+    tx.commit().map_err(Error::Database)?;
+
+    // id is unique, so the number of rows updated should be 0 or 1
+    Ok(updated == 1)
+}
+
+fn insert_audit_entry(
+    conn: &rusqlite::Connection,
+    id: PollID,
+    entry: &AuditEntry,
+) -> Result<(), Error> {
+    conn.execute(
+        "INSERT INTO audit_log (poll, time, action, details) VALUES (?1, ?2, ?3, ?4)",
+        rusqlite::params![
+            id.index(),
+            entry.time.to_rfc3339(),
+            entry.action,
+            entry.details
+        ],
+    )
+    .map_err(Error::Insert)
+    .map(|_| ())
+}
+
+/// Adds a record of an admin action to the poll's audit log
+pub async fn log_action(pool: &DbPool, id: PollID, entry: &AuditEntry) -> Result<(), Error> {
+    let conn = pool.get().map_err(Error::Connection)?;
+    insert_audit_entry(&conn, id, entry)
+}
+
+/// Retrieves the poll's audit log, the newest records first
+pub async fn audit_log(pool: &DbPool, id: PollID) -> Result<Vec<AuditEntry>, Error> {
+    let conn = pool.get().map_err(Error::Connection)?;
+
+    let mut query = conn
+        .prepare("SELECT time, action, details FROM audit_log WHERE poll = ?1 ORDER BY rowid DESC")
+        .map_err(Error::Query)?;
+
+    let entries: Result<Vec<AuditEntry>, rusqlite::Error> = query
+        .query_map([id.index()], |row| {
+            Ok(AuditEntry {
+                time: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(0)?)
+                    .map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(0, Type::Text, e.into())
+                    })?
+                    .into(),
+                action: row.get(1)?,
+                details: row.get(2)?,
+            })
+        })
+        .map_err(Error::Query)?
+        .collect();
+
+    entries.map_err(Error::Database)
+}
+
+/// Registers a webhook of a poll and returns its id
+pub async fn add_webhook(pool: &DbPool, id: PollID, url: &str, secret: &str) -> Result<i64, Error> {
+    let conn = pool.get().map_err(Error::Connection)?;
+    conn.execute(
+        "INSERT INTO webhooks (poll, url, secret) VALUES (?1, ?2, ?3)",
+        rusqlite::params![id.index(), url, secret],
+    )
+    .map_err(Error::Insert)?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Removes a webhook of a poll along with its deliveries, returns true if a webhook was removed
+pub async fn remove_webhook(pool: &DbPool, id: PollID, webhook: i64) -> Result<bool, Error> {
+    let mut conn = pool.get().map_err(Error::Connection)?;
+
+    let tx = conn.transaction().map_err(Error::Database)?;
+    let removed = tx
+        .execute(
+            "DELETE FROM webhooks WHERE id = ?1 AND poll = ?2",
+            rusqlite::params![webhook, id.index()],
+        )
+        .map_err(Error::Query)?;
+    tx.execute(
+        "DELETE FROM webhook_deliveries WHERE webhook = ?1 AND poll = ?2",
+        rusqlite::params![webhook, id.index()],
+    )
+    .map_err(Error::Query)?;
+    tx.commit().map_err(Error::Database)?;
+
+    Ok(removed == 1)
+}
+
+/// Retrieves the webhooks of a poll, in the order they were added
+pub async fn get_webhooks(pool: &DbPool, id: PollID) -> Result<Vec<Webhook>, Error> {
+    let conn = pool.get().map_err(Error::Connection)?;
+
+    let mut query = conn
+        .prepare("SELECT id, url, secret FROM webhooks WHERE poll = ?1 ORDER BY id")
+        .map_err(Error::Query)?;
+
+    let webhooks: Result<Vec<Webhook>, rusqlite::Error> = query
+        .query_map([id.index()], |row| {
+            Ok(Webhook {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                secret: row.get(2)?,
+            })
+        })
+        .map_err(Error::Query)?
+        .collect();
+
+    webhooks.map_err(Error::Database)
+}
+
+/// Adds a delivery attempt to the poll's delivery log, keeping only the newest
+/// DELIVERY_LOG_LENGTH entries. Returns false (without logging anything) if the
+/// webhook was removed in the meantime
+pub async fn log_delivery(
+    pool: &DbPool,
+    id: PollID,
+    webhook: i64,
+    delivery: &Delivery,
+) -> Result<bool, Error> {
+    let mut conn = pool.get().map_err(Error::Connection)?;
+
+    let tx = conn.transaction().map_err(Error::Database)?;
+    // Webhook ids are never reused, unlike poll ids
+    let inserted = tx
+        .execute(
+            "INSERT INTO webhook_deliveries (poll, webhook, url, time, event, attempt, result)
+            SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7
+            WHERE EXISTS (SELECT 1 FROM webhooks WHERE id = ?2 AND poll = ?1)",
+            rusqlite::params![
+                id.index(),
+                webhook,
+                delivery.url,
+                delivery.time.to_rfc3339(),
+                delivery.event,
+                delivery.attempt,
+                delivery.result
+            ],
+        )
+        .map_err(Error::Insert)?;
+    tx.execute(
+        "DELETE FROM webhook_deliveries WHERE poll = ?1 AND rowid NOT IN
+        (SELECT rowid FROM webhook_deliveries WHERE poll = ?1 ORDER BY rowid DESC LIMIT ?2)",
+        rusqlite::params![id.index(), DELIVERY_LOG_LENGTH],
+    )
+    .map_err(Error::Query)?;
+    tx.commit().map_err(Error::Database)?;
+
+    Ok(inserted == 1)
+}
+
+/// Retrieves the poll's webhook delivery log, the newest attempts first
+pub async fn webhook_deliveries(pool: &DbPool, id: PollID) -> Result<Vec<Delivery>, Error> {
+    let conn = pool.get().map_err(Error::Connection)?;
+
+    let mut query = conn
+        .prepare(
+            "SELECT url, time, event, attempt, result FROM webhook_deliveries
+            WHERE poll = ?1 ORDER BY rowid DESC",
+        )
+        .map_err(Error::Query)?;
+
+    let deliveries: Result<Vec<Delivery>, rusqlite::Error> = query
+        .query_map([id.index()], |row| {
+            Ok(Delivery {
+                url: row.get(0)?,
+                time: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(1)?)
+                    .map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(1, Type::Text, e.into())
+                    })?
+                    .into(),
+                event: row.get(2)?,
+                attempt: row.get(3)?,
+                result: row.get(4)?,
+            })
+        })
+        .map_err(Error::Query)?
+        .collect();
+
+    deliveries.map_err(Error::Database)
+}
+
+/// Retrieves the stored ballots of a poll, in the order they were cast
+pub async fn get_ballots(pool: &DbPool, id: PollID) -> Result<Vec<Ballot>, Error> {
+    let conn = pool.get().map_err(Error::Connection)?;
+
+    let mut query = conn
+        .prepare("SELECT ranks FROM ballots WHERE poll = ?1 ORDER BY rowid")
+        .map_err(Error::Query)?;
+
+    let ballots: Result<Vec<Ballot>, rusqlite::Error> = query
+        .query_map([id.index()], |row| {
+            Ballot::from_db(&row.get::<_, String>(0)?)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, e.into()))
+        })
+        .map_err(Error::Query)?
+        .collect();
+
+    ballots.map_err(Error::Database)
+}
+
+/// Deletes the stored ballots of a poll, used when resetting its votes
+pub async fn delete_ballots(pool: &DbPool, id: PollID) -> Result<usize, Error> {
     pool.get()
         .map_err(Error::Connection)?
-        .execute("DELETE FROM polls WHERE id IS NOT NULL", [])
+        .execute("DELETE FROM ballots WHERE poll = ?1", [id.index()])
         .map_err(Error::Query)
 }
 
-/// Deletes a poll, returns true if a poll was deleted
-pub async fn delete_poll(pool: &DbPool, id: PollID) -> Result<bool, Error> {
+/// Replaces the poll's admin token
+/// Returns true if a poll was updated
+pub async fn update_admin_token(
+    pool: &DbPool,
+    id: PollID,
+    admin_token: &str,
+) -> Result<bool, Error> {
     pool.get()
         .map_err(Error::Connection)?
-        .execute("DELETE FROM polls WHERE id = ?1", [id.index()])
+        .execute(
+            "UPDATE polls SET admin_link = ?2 WHERE id = ?1",
+            rusqlite::params![id.index(), admin_token],
+        )
         .map_err(Error::Query)
         // id is unique, so the number of rows updated should be 0 or 1
         .map(|u| u == 1)
 }
 
+/// Completely clears the polls table (and their ballots, logs and webhooks), returns number of deleted polls
+pub async fn purge(pool: &DbPool) -> Result<usize, Error> {
+    let mut conn = pool.get().map_err(Error::Connection)?;
+
+    let tx = conn.transaction().map_err(Error::Database)?;
+    let deleted = tx
+        .execute("DELETE FROM polls WHERE id IS NOT NULL", [])
+        .map_err(Error::Query)?;
+    // Poll ids are reused, so ballots, logs and webhooks can't outlive their poll
+    tx.execute("DELETE FROM ballots", [])
+        .map_err(Error::Query)?;
+    tx.execute("DELETE FROM audit_log", [])
+        .map_err(Error::Query)?;
+    tx.execute("DELETE FROM webhooks", [])
+        .map_err(Error::Query)?;
+    tx.execute("DELETE FROM webhook_deliveries", [])
+        .map_err(Error::Query)?;
+    tx.commit().map_err(Error::Database)?;
+
+    Ok(deleted)
+}
+
+/// Deletes a poll, its ballots, audit log and webhooks, returns true if a poll was deleted
+pub async fn delete_poll(pool: &DbPool, id: PollID) -> Result<bool, Error> {
+    let mut conn = pool.get().map_err(Error::Connection)?;
+
+    let tx = conn.transaction().map_err(Error::Database)?;
+    let deleted = tx
+        .execute("DELETE FROM polls WHERE id = ?1", [id.index()])
+        .map_err(Error::Query)?;
+    // Poll ids are reused, so ballots, logs and webhooks can't outlive their poll
+    tx.execute("DELETE FROM ballots WHERE poll = ?1", [id.index()])
+        .map_err(Error::Query)?;
+    tx.execute("DELETE FROM audit_log WHERE poll = ?1", [id.index()])
+        .map_err(Error::Query)?;
+    tx.execute("DELETE FROM webhooks WHERE poll = ?1", [id.index()])
+        .map_err(Error::Query)?;
+    tx.execute(
+        "DELETE FROM webhook_deliveries WHERE poll = ?1",
+        [id.index()],
+    )
+    .map_err(Error::Query)?;
+    tx.commit().map_err(Error::Database)?;
+
+    // id is unique, so the number of rows updated should be 0 or 1
+    Ok(deleted == 1)
+}
+
+/// Returns the session key stored in the database, storing `generated` first if there's none.
+/// Used when POLL_SESSION_KEY isn't set, so that restarts don't invalidate the cookies.
+pub async fn session_key(pool: &DbPool, generated: &str) -> Result<String, Error> {
+    let conn = pool.get().map_err(Error::Connection)?;
+    conn.execute(
+        "INSERT OR IGNORE INTO settings (name, value) VALUES ('session_key', ?1)",
+        [generated],
+    )
+    .map_err(Error::Insert)?;
+    conn.query_row(
+        "SELECT value FROM settings WHERE name = 'session_key'",
+        [],
+        |row| row.get(0),
+    )
+    .map_err(Error::Query)
+}
+
 /// Retrieves *ALL POLLS*. If there are a lot of polls, this can be very slow or fail spectacularly.
 pub async fn list_polls(pool: &DbPool) -> Result<Vec<crate::templates::PollInfo>, Error> {
     let conn = pool.get().map_err(Error::Connection)?;
@@ -193,3 +841,81 @@ pub async fn list_polls(pool: &DbPool) -> Result<Vec<crate::templates::PollInfo>
 
     polls.map_err(Error::Database)
 }
+
+#[test]
+fn test_session_key() {
+    use futures::executor::block_on;
+
+    let dir = std::env::temp_dir().join(format!("pollinator-session-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let pool = block_on(init(&dir.join("main.db"))).unwrap();
+    assert_eq!(block_on(session_key(&pool, "first")).unwrap(), "first");
+    // The key generated on the next start is ignored
+    assert_eq!(block_on(session_key(&pool, "second")).unwrap(), "first");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_import_polls() {
+    use futures::executor::block_on;
+
+    let dir = std::env::temp_dir().join(format!("pollinator-import-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let pool = block_on(init(&dir.join("main.db"))).unwrap();
+    let ptype = PollType::try_parse("RankedBorda").unwrap();
+    let poll = |name: &str| Poll {
+        data: PollData {
+            // The index is replaced by the database's
+            id: PollID::generate(1),
+            ptype,
+            name: name.to_string(),
+            date_created: chrono::Utc::now(),
+            admin_link: "token".to_string(),
+            voters: 1,
+            settings: PollSettings::default(),
+        },
+        format: crate::poll::create_poll_format_from_data(ptype, "A,B").unwrap(),
+    };
+    block_on(insert_poll(&pool, poll("Existing"))).unwrap();
+
+    let ballot = Ballot::parse("0=1&1=0", 2).unwrap();
+    let imported = vec![(poll("A"), vec![ballot.clone()]), (poll("B"), vec![])];
+    let ids = block_on(import_polls(&pool, &imported)).unwrap();
+    assert_eq!(ids.iter().map(PollID::index).collect::<Vec<_>>(), [2, 3]);
+    assert_eq!(block_on(get_poll(&pool, ids[0])).unwrap().data.name, "A");
+    assert_eq!(block_on(get_ballots(&pool, ids[0])).unwrap(), [ballot]);
+    assert!(block_on(get_ballots(&pool, ids[1])).unwrap().is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_restore_old_backup() {
+    use futures::executor::block_on;
+
+    let dir = std::env::temp_dir().join(format!("pollinator-restore-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // A backup made before any migrations
+    let backup = dir.join("old.db");
+    let format = crate::poll::create_poll_format_from_data(PollType::Single, "A,B").unwrap();
+    let conn = rusqlite::Connection::open(&backup).unwrap();
+    conn.execute_batch(include_str!("../db/db.sql")).unwrap();
+    conn.execute(
+        "INSERT INTO polls (randpart, type, name, date_created, admin_link, voters, format_data) VALUES ('AAAAAAAAAAE', 'Single', 'Old', ?1, 'token', 0, ?2)",
+        rusqlite::params![chrono::Utc::now().to_rfc3339(), format.save_state().unwrap()],
+    )
+    .unwrap();
+    drop(conn);
+
+    let pool = block_on(init(&dir.join("main.db"))).unwrap();
+    assert_eq!(block_on(restore(&pool, &backup)).unwrap(), 1);
+    assert_eq!(block_on(schema_version(&pool)).unwrap(), SCHEMA_VERSION);
+    let polls = block_on(all_polls(&pool)).unwrap();
+    assert_eq!(polls[0].data.name, "Old");
+    assert!(!polls[0].data.settings.dedupe);
+    // The backup itself is left as it was
+    assert_eq!(block_on(validate_backup(&backup)).unwrap(), 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
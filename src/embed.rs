@@ -0,0 +1,201 @@
+//! Voting and results widgets for embedding polls in other websites in frames,
+//! and an oEmbed endpoint describing them.
+//! Only the websites set in POLL_EMBED_ANCESTORS can frame the widgets, see security::EmbedPolicy.
+
+use crate::db::DbPool;
+use crate::poll::PollID;
+use crate::rate::{self, Route};
+use crate::security::{CsrfToken, EmbedPolicy};
+use crate::*;
+use askama::Template;
+use serde::{Deserialize, Serialize};
+
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+
+/// The width of widgets if the consumer doesn't limit it, the same as the width of charts
+const DEFAULT_WIDTH: u32 = 640;
+
+/// Handles the voting widget: the poll's voting form without the rest of the page
+pub async fn handle_embed_vote(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+    csrf: CsrfToken,
+    sessions: web::Data<session::SessionConfig>,
+    challenges: web::Data<pow::Challenges>,
+    policy: web::Data<EmbedPolicy>,
+) -> Result<HttpResponse> {
+    let content = vote_page(&req, &db, &poll_id, &csrf, &sessions, &challenges, true).await?;
+    Ok(policy.response().body(content))
+}
+
+/// Handles the voting widget's callback, the same as the voting callback
+/// but the response can be framed
+pub async fn handle_embed_vote_desc(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+    params: String,
+    sessions: web::Data<session::SessionConfig>,
+    challenges: web::Data<pow::Challenges>,
+    policy: web::Data<EmbedPolicy>,
+) -> Result<HttpResponse> {
+    cast_vote(
+        &req,
+        &db,
+        &poll_id,
+        &params,
+        &sessions,
+        &challenges,
+        Some(&policy),
+    )
+    .await
+}
+
+/// Handles the results widget: the results chart and the number of voters
+pub async fn handle_embed_results(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+    policy: web::Data<EmbedPolicy>,
+) -> Result<HttpResponse> {
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    rate::limit(&req, Route::Results, None)?;
+
+    let poll = db::get_poll(&db, poll_id).await?;
+    let content = templates::EmbedResultsTemplate {
+        poll: &poll.data,
+        results_link: req
+            .url_for("results", [poll_id.to_string()])
+            .unwrap()
+            .as_str(),
+        vote_link: req.url_for("vote", [poll_id.to_string()]).unwrap().as_str(),
+    }
+    .render()
+    .map_err(|e| UserError::InternalError(e.into()))?;
+
+    Ok(policy.response().body(content))
+}
+
+#[derive(Deserialize)]
+pub struct OEmbedParams {
+    url: String,
+    maxwidth: Option<u32>,
+    maxheight: Option<u32>,
+    format: Option<String>,
+}
+
+/// An oEmbed (https://oembed.com) response of the "rich" type
+#[derive(Serialize)]
+struct OEmbed {
+    version: &'static str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    provider_name: &'static str,
+    provider_url: String,
+    title: String,
+    html: String,
+    width: u32,
+    height: u32,
+}
+
+/// Handles oEmbed requests for the voting and results pages (and widgets) of polls
+/// Params:
+///  - url: The address of a voting or results page, like {website}/vote/{poll_id}
+///  - maxwidth, maxheight: The largest size of the widget the consumer can show
+///  - format: Only "json" is supported
+///
+/// Returns the widget in an iframe.
+pub async fn handle_oembed(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    params: web::Query<OEmbedParams>,
+) -> Result<HttpResponse> {
+    if params.format.as_deref().unwrap_or("json") != "json" {
+        return Ok(HttpResponse::NotImplemented().finish());
+    }
+    let (widget, poll_id) = match embedded_poll(&params.url) {
+        Some(embedded) => embedded,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    rate::limit(&req, Route::Results, None)?;
+
+    let poll = db::get_poll(&db, poll_id).await?;
+    let options = poll.format.export_state().options.len() as u32;
+    // Enough to show every option without scrolling in most browsers
+    let height = match widget {
+        "embed_vote" => 140 + 44 * options,
+        _ => 150 + 28 * options,
+    };
+    let width = params
+        .maxwidth
+        .map_or(DEFAULT_WIDTH, |max| max.min(DEFAULT_WIDTH));
+    let height = params.maxheight.map_or(height, |max| max.min(height));
+    let src = req.url_for(widget, [poll_id.to_string()]).unwrap();
+
+    let response = OEmbed {
+        version: "1.0",
+        kind: "rich",
+        provider_name: "Pollinator",
+        provider_url: req.url_for_static("index").unwrap().to_string(),
+        html: format!(
+            r#"<iframe src="{}" width="{}" height="{}" title="{}" frameborder="0"></iframe>"#,
+            src,
+            width,
+            height,
+            askama::MarkupDisplay::new_unsafe(&poll.data.name, askama::Html),
+        ),
+        title: poll.data.name,
+        width,
+        height,
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Returns the name of the widget route and the poll id of a voting or results page address
+fn embedded_poll(url: &str) -> Option<(&'static str, String)> {
+    // The path, without the scheme, host, query or fragment
+    let path = url.split_once("://").map_or(url, |(_, rest)| {
+        rest.find('/').map_or("", |start| &rest[start..])
+    });
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let path = path.strip_prefix("/embed").unwrap_or(path);
+
+    let (widget, poll_id) = if let Some(id) = path.strip_prefix("/vote/") {
+        ("embed_vote", id)
+    } else if let Some(id) = path.strip_prefix("/results/") {
+        ("embed_results", id)
+    } else {
+        return None;
+    };
+    // Poll ids contain a '+', which is often escaped or decoded as a space
+    let poll_id = poll_id
+        .trim_end_matches('/')
+        .replace("%2B", "+")
+        .replace("%2b", "+")
+        .replace(' ', "+");
+    (!poll_id.is_empty() && !poll_id.contains('/')).then_some((widget, poll_id))
+}
+
+#[test]
+fn test_embedded_poll() {
+    assert_eq!(
+        embedded_poll("https://polls.example.com/vote/5+abc"),
+        Some(("embed_vote", "5+abc".to_string()))
+    );
+    assert_eq!(
+        embedded_poll("http://localhost:8080/embed/results/5%2Babc?x=1#top"),
+        Some(("embed_results", "5+abc".to_string()))
+    );
+    assert_eq!(
+        embedded_poll("/results/5 abc/"),
+        Some(("embed_results", "5+abc".to_string()))
+    );
+    assert_eq!(embedded_poll("https://polls.example.com/admin/5+abc"), None);
+    assert_eq!(
+        embedded_poll("https://polls.example.com/vote/5+abc/response"),
+        None
+    );
+    assert_eq!(embedded_poll("https://polls.example.com"), None);
+}
@@ -1,4 +1,5 @@
-use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -9,14 +10,34 @@ pub enum UserError {
     PollCreation(#[source] anyhow::Error),
     #[error("Failed to vote on poll")]
     Voting(#[source] anyhow::Error),
+    #[error("Failed to import ballots")]
+    Import(#[source] anyhow::Error),
+    #[error("Invalid webhook")]
+    Webhook(#[source] anyhow::Error),
+    /// Contains the time after which the request will be allowed
     #[error("Too many requests")]
-    TooManyRequests,
+    TooManyRequests(std::time::Duration),
     #[error("Invalid admin token specified")]
     InvalidAdminToken,
     #[error("Admin functions are disabled on this server")]
     AdminOff,
     #[error("Invalid admin action")]
     InvalidAdminAction,
+    #[error("Backups are not configured on this server")]
+    BackupsOff,
+    #[error("Webhooks are disabled on this server")]
+    WebhooksOff,
+    #[error("Invalid or missing CSRF token")]
+    InvalidCsrfToken,
+    #[error("You have already voted on this poll")]
+    AlreadyVoted,
+    #[error("Invalid or expired proof of work. Reload the page and try again")]
+    InvalidProofOfWork,
+    #[error("The server is restarting. Try again in a moment")]
+    ShuttingDown,
+    /// Contains the number of stored ballots and the number of voters
+    #[error("Only {0} of the poll's {1} ballots are stored (votes cast before ballots were kept only count towards the tallies), so they can't be exported or charted")]
+    MissingBallots(u64, u64),
 }
 
 impl ResponseError for UserError {
@@ -24,9 +45,13 @@ impl ResponseError for UserError {
         use UserError::*;
         match *self {
             InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            PollCreation(_) | Voting(_) | AdminOff | InvalidAdminAction => StatusCode::BAD_REQUEST,
+            PollCreation(_) | Voting(_) | Import(_) | Webhook(_) | AdminOff
+            | InvalidAdminAction | BackupsOff | WebhooksOff => StatusCode::BAD_REQUEST,
             InvalidAdminToken => StatusCode::UNAUTHORIZED,
-            TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            InvalidCsrfToken | AlreadyVoted | InvalidProofOfWork => StatusCode::FORBIDDEN,
+            ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            MissingBallots(..) => StatusCode::CONFLICT,
         }
     }
 
@@ -36,8 +61,13 @@ impl ResponseError for UserError {
         req.content_type("text/plain; charset=utf-8");
 
         match self {
-            TooManyRequests => req.body(include_str!("../static/limit.html")),
-            InternalError(e) | PollCreation(e) | Voting(e) => {
+            TooManyRequests(retry_after) => {
+                // Round up, so that retrying right after the time passes always succeeds
+                let secs = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+                req.insert_header((header::RETRY_AFTER, secs))
+                    .body(include_str!("../static/limit.html"))
+            }
+            InternalError(e) | PollCreation(e) | Voting(e) | Import(e) | Webhook(e) => {
                 // TODO: When std::error::Report stabilizes, use it instead
                 req.body(format!("{}: {}", self, e))
             }
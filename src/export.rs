@@ -0,0 +1,260 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::poll::{
+    create_poll_format_from_portable, Ballot, Poll, PollData, PollID, PollSettings, PollType,
+    PortableState,
+};
+
+/// The version of the export format, increased whenever it changes
+pub const EXPORT_VERSION: u32 = 1;
+
+/// A set of polls that can be moved to another instance
+#[derive(Serialize, Deserialize)]
+pub struct Export {
+    pub version: u32,
+    pub polls: Vec<ExportedPoll>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportedPoll {
+    /// The id the poll had on the instance it was exported from
+    pub id: String,
+    pub r#type: String,
+    pub name: String,
+    /// RFC 3339
+    pub date_created: String,
+    pub admin_token: String,
+    pub voters: u64,
+    #[serde(default)]
+    pub dedupe: bool,
+    #[serde(flatten)]
+    pub state: PortableState,
+    /// The stored ballots of ranked polls, see Ballot
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ballots: Vec<Ballot>,
+}
+
+impl ExportedPoll {
+    pub fn new(poll: &Poll, ballots: Vec<Ballot>) -> Self {
+        ExportedPoll {
+            id: poll.data.id.to_string(),
+            r#type: poll.data.ptype.to_string(),
+            name: poll.data.name.clone(),
+            date_created: poll.data.date_created.to_rfc3339(),
+            admin_token: poll.data.admin_link.clone(),
+            voters: poll.data.voters,
+            dedupe: poll.data.settings.dedupe,
+            state: poll.format.export_state(),
+            ballots,
+        }
+    }
+
+    /// Restores the poll under a new id, along with its ballots
+    pub fn into_poll(self, id: PollID) -> anyhow::Result<(Poll, Vec<Ballot>)> {
+        let ptype = PollType::try_parse(&self.r#type)?;
+        if !self.ballots.is_empty() && !ptype.has_ballots() {
+            anyhow::bail!("{} polls don't have ballots", ptype);
+        }
+        for ballot in &self.ballots {
+            ballot.check(self.state.options.len())?;
+        }
+        let poll = Poll {
+            data: PollData {
+                id,
+                ptype,
+                name: self.name,
+                date_created: chrono::DateTime::parse_from_rfc3339(&self.date_created)
+                    .context("Invalid creation date")?
+                    .into(),
+                admin_link: self.admin_token,
+                voters: self.voters,
+                settings: PollSettings {
+                    dedupe: self.dedupe,
+                },
+            },
+            format: create_poll_format_from_portable(ptype, &self.state)?,
+        };
+        Ok((poll, self.ballots))
+    }
+}
+
+impl Export {
+    pub fn new(polls: Vec<ExportedPoll>) -> Self {
+        Export {
+            version: EXPORT_VERSION,
+            polls,
+        }
+    }
+
+    /// Reads an export, checking that its version is supported
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+
+        let Version { version } = serde_json::from_str(json).context("Invalid export file")?;
+        if version != EXPORT_VERSION {
+            anyhow::bail!("Unsupported export version: {}", version);
+        }
+        serde_json::from_str(json).context("Invalid export file")
+    }
+}
+
+/// The public results of a poll, served by handle_results_export.
+/// The same as an exported poll without the admin token.
+#[derive(Serialize)]
+pub struct PollResults<'a> {
+    pub id: String,
+    pub r#type: String,
+    pub name: &'a str,
+    /// RFC 3339
+    pub date_created: String,
+    pub voters: u64,
+    /// In the order the options were created in, not sorted by the results
+    #[serde(flatten)]
+    pub state: PortableState,
+}
+
+impl<'a> PollResults<'a> {
+    pub fn new(poll: &'a Poll) -> Self {
+        PollResults {
+            id: poll.data.id.to_string(),
+            r#type: poll.data.ptype.to_string(),
+            name: &poll.data.name,
+            date_created: poll.data.date_created.to_rfc3339(),
+            voters: poll.data.voters,
+            state: poll.format.export_state(),
+        }
+    }
+
+    /// A row with each option and its tally, after a header
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("option,tally\r\n");
+        for option in &self.state.options {
+            csv.push_str(&format!("{},{}\r\n", csv_field(&option.name), option.tally));
+        }
+        csv
+    }
+}
+
+/// Quotes a CSV field if needed (RFC 4180). Fields that spreadsheets would run as formulas
+/// get a `'` in front, since option names are chosen by poll creators.
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    let field: std::borrow::Cow<'_, str> = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", field).into()
+    } else {
+        field.into()
+    };
+    if field.contains(['"', ',', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field
+    }
+}
+
+#[test]
+fn test_export_import() {
+    use crate::poll::create_poll_format_from_data;
+
+    let mut polls = Vec::new();
+    for (id, ptype, data, vote) in [
+        (1, "Single", "a,b,c", "response=1"),
+        (2, "RankedDowdall", "a,b,c", "0=2&1=0&2=1"),
+        (3, "Score", "a,b,0,5", "0=5&1=3"),
+    ] {
+        let ptype = PollType::try_parse(ptype).unwrap();
+        let mut format = create_poll_format_from_data(ptype, data).unwrap();
+        format.register_votes(vote).unwrap();
+        polls.push(Poll {
+            data: PollData {
+                id: PollID::new(id, 42),
+                ptype,
+                name: format!("Poll {}", id),
+                date_created: chrono::Utc::now(),
+                admin_link: "token".to_string(),
+                voters: 1,
+                settings: PollSettings::default(),
+            },
+            format,
+        });
+    }
+
+    let ballots = vec![Ballot(vec![2, 0, 1])];
+    let exported = polls
+        .iter()
+        .map(|poll| ExportedPoll::new(poll, ballots.clone()))
+        .collect();
+    let json = serde_json::to_string(&Export::new(exported)).unwrap();
+    assert!(json.contains(r#""points":[0,5]"#));
+    let mut export = Export::from_json(&json).unwrap();
+    // Only ranked polls have ballots
+    assert!(export.polls.remove(0).into_poll(PollID::new(7, 7)).is_err());
+    for (mut exported, poll) in export.polls.into_iter().zip(&polls[1..]) {
+        if !poll.data.ptype.has_ballots() {
+            exported.ballots.clear();
+        }
+        let (imported, imported_ballots) = exported.into_poll(PollID::new(7, 7)).unwrap();
+        if poll.data.ptype.has_ballots() {
+            assert_eq!(imported_ballots, ballots);
+        }
+        assert_eq!(imported.data.name, poll.data.name);
+        assert_eq!(imported.format.export_state(), poll.format.export_state());
+        assert_eq!(
+            imported.format.save_state().unwrap(),
+            poll.format.save_state().unwrap()
+        );
+    }
+
+    assert!(Export::from_json(r#"{"version":2,"polls":[]}"#).is_err());
+}
+
+#[test]
+fn test_results_csv() {
+    use crate::poll::{PortableOption, Tally};
+
+    let option = |name: &str, tally| PortableOption {
+        name: name.to_string(),
+        tally,
+    };
+    let results = PollResults {
+        id: "1+AAAAAAAAAAA".to_string(),
+        r#type: "RankedDowdall".to_string(),
+        name: "Test",
+        date_created: String::new(),
+        voters: 2,
+        state: PortableState {
+            options: vec![
+                option("plain", Tally::Points(1.5)),
+                option("with, comma", Tally::Points(0.0)),
+                option("\"quoted\"", Tally::Count(3)),
+            ],
+            points: None,
+        },
+    };
+    assert_eq!(
+        results.to_csv(),
+        "option,tally\r\nplain,1.5\r\n\"with, comma\",0\r\n\"\"\"quoted\"\"\",3\r\n"
+    );
+}
+
+#[test]
+fn test_csv_formulas() {
+    for (name, field) in [
+        (
+            "=HYPERLINK(\"http://example.com\")",
+            "\"'=HYPERLINK(\"\"http://example.com\"\")\"",
+        ),
+        ("@SUM(A1:A2)", "'@SUM(A1:A2)"),
+        ("+1", "'+1"),
+        ("-1+2", "'-1+2"),
+        ("\tcmd", "'\tcmd"),
+        ("\r=1", "\"'\r=1\""),
+        ("=1,2", "\"'=1,2\""),
+        ("a=b", "a=b"),
+        ("1-2", "1-2"),
+    ] {
+        assert_eq!(csv_field(name), field, "{}", name);
+    }
+}
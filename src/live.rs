@@ -0,0 +1,139 @@
+//! Live results: results pages subscribe to a poll's Server-Sent Events stream
+//! (/results/{poll_id}/events) and get its new tallies after every vote.
+
+use actix_web::web::Bytes;
+use futures::channel::mpsc;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::export::PollResults;
+use crate::poll::{Poll, PollID};
+
+/// The most streams open at once, across all polls
+pub const MAX_SUBSCRIBERS: usize = 10_000;
+/// The most streams a single client (see rate::client_key) can have open at once,
+/// so that one client can't use up MAX_SUBSCRIBERS
+pub const MAX_CLIENT_SUBSCRIBERS: usize = 20;
+/// Every event has the whole results, so a client that falls behind can skip some
+const QUEUE_LENGTH: usize = 4;
+/// The time between comments sent to keep idle streams open
+pub const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Keeps the streams of every poll and sends them the poll's results
+#[derive(Default)]
+pub struct Hub {
+    subscribers: Mutex<HashMap<PollID, Vec<Subscriber>>>,
+    /// Set when the server shuts down, see Hub::close
+    closed: AtomicBool,
+}
+
+/// An open stream and the client it was opened by (None for clients that aren't limited)
+struct Subscriber {
+    client: Option<IpAddr>,
+    sender: mpsc::Sender<Bytes>,
+}
+
+impl Hub {
+    /// Returns a stream of a poll's results events, or None if too many streams are open
+    /// (in total or by the client) or the server is shutting down
+    pub fn subscribe(
+        &self,
+        poll_id: PollID,
+        client: Option<IpAddr>,
+    ) -> Option<mpsc::Receiver<Bytes>> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if self.closed.load(Ordering::Relaxed) {
+            return None;
+        }
+        // Streams are closed when the client disconnects
+        subscribers.retain(|_, senders| {
+            senders.retain(|subscriber| !subscriber.sender.is_closed());
+            !senders.is_empty()
+        });
+        if subscribers.values().map(Vec::len).sum::<usize>() >= MAX_SUBSCRIBERS {
+            return None;
+        }
+        if client.is_some() {
+            let open = subscribers
+                .values()
+                .flatten()
+                .filter(|subscriber| subscriber.client == client)
+                .count();
+            if open >= MAX_CLIENT_SUBSCRIBERS {
+                return None;
+            }
+        }
+        let (sender, receiver) = mpsc::channel(QUEUE_LENGTH);
+        subscribers
+            .entry(poll_id)
+            .or_default()
+            .push(Subscriber { client, sender });
+        Some(receiver)
+    }
+
+    /// The number of open streams, across all polls
+    pub fn subscribers(&self) -> usize {
+        let subscribers = self.subscribers.lock().unwrap();
+        subscribers
+            .values()
+            .flatten()
+            .filter(|subscriber| !subscriber.sender.is_closed())
+            .count()
+    }
+
+    /// Ends all streams, so that open results pages don't keep the server from shutting down.
+    /// Browsers reconnect to them on their own.
+    pub fn close(&self) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        self.closed.store(true, Ordering::Relaxed);
+        subscribers.clear();
+    }
+
+    /// Sends the poll's current results to everyone watching it
+    pub fn publish(&self, poll: &Poll) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let Some(senders) = subscribers.get_mut(&poll.data.id) else {
+            return;
+        };
+        let event = event(poll);
+        senders.retain_mut(
+            |subscriber| match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(e) => !e.is_disconnected(),
+            },
+        );
+        if senders.is_empty() {
+            subscribers.remove(&poll.data.id);
+        }
+    }
+}
+
+/// A "results" event with the same data as /results/{poll_id}.json
+pub fn event(poll: &Poll) -> Bytes {
+    // Serializing numbers and strings can't fail
+    let data = serde_json::to_string(&PollResults::new(poll)).unwrap_or_default();
+    Bytes::from(format!("event: results\ndata: {}\n\n", data))
+}
+
+#[test]
+fn test_client_subscribers() {
+    let hub = Hub::default();
+    let poll_id = PollID::new(1, 0);
+    let a: Option<IpAddr> = Some("192.0.2.1".parse().unwrap());
+    let b: Option<IpAddr> = Some("192.0.2.2".parse().unwrap());
+
+    let mut streams: Vec<_> = (0..MAX_CLIENT_SUBSCRIBERS)
+        .map(|_| hub.subscribe(poll_id, a).unwrap())
+        .collect();
+    assert!(hub.subscribe(poll_id, a).is_none());
+    assert!(hub.subscribe(PollID::new(2, 0), a).is_none());
+    // Other clients and clients that aren't limited can still subscribe
+    assert!(hub.subscribe(poll_id, b).is_some());
+    assert!(hub.subscribe(poll_id, None).is_some());
+    // Closed streams don't count
+    streams.pop();
+    assert!(hub.subscribe(poll_id, a).is_some());
+}
@@ -0,0 +1,159 @@
+//! Logging setup and request IDs. Every request gets an ID (see request_id) that's
+//! sent back in the X-Request-Id header and added to everything logged while handling it.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::HttpMessage;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+use crate::util;
+
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// The longest request ID accepted from reverse proxies
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: RequestId;
+}
+
+/// The format of log lines
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// env_logger's human-readable lines
+    #[default]
+    Text,
+    /// A JSON object per line, for log collectors
+    Json,
+}
+
+impl LogFormat {
+    pub fn try_parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// A log line in the JSON format
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    level: &'a str,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+    message: String,
+}
+
+/// Sets up the logger. The level is set with RUST_LOG ("info" by default).
+pub fn init(format: LogFormat) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let request_id = current_request_id();
+            write!(
+                buf,
+                "[{} {:<5} {}",
+                buf.timestamp(),
+                buf.default_styled_level(record.level()),
+                record.target()
+            )?;
+            if let Some(id) = request_id {
+                write!(buf, " request={}", id.0)?;
+            }
+            writeln!(buf, "] {}", record.args())
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let request_id = current_request_id();
+            let line = JsonLine {
+                time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                level: record.level().as_str(),
+                target: record.target(),
+                request_id: request_id.as_ref().map(|id| id.0.as_str()),
+                message: record.args().to_string(),
+            };
+            serde_json::to_writer(&mut *buf, &line)?;
+            writeln!(buf)
+        }),
+    };
+    builder.init();
+}
+
+/// The ID of a request, also stored in its extensions
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Reuses the ID set by a reverse proxy if it looks safe to log, otherwise makes a new one
+    fn from_header(value: Option<&HeaderValue>) -> Self {
+        match value.and_then(|v| v.to_str().ok()) {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LENGTH
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)) =>
+            {
+                RequestId(id.to_string())
+            }
+            _ => RequestId(util::random_base64_u64()),
+        }
+    }
+}
+
+fn current_request_id() -> Option<RequestId> {
+    REQUEST_ID.try_with(RequestId::clone).ok()
+}
+
+/// Middleware giving every request an ID, which is added to log lines and responses.
+/// Server errors are logged here, along with the ID.
+pub async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = RequestId::from_header(req.headers().get(REQUEST_ID_HEADER));
+    req.extensions_mut().insert(id.clone());
+
+    REQUEST_ID
+        .scope(id.clone(), async move {
+            let mut res = next.call(req).await?;
+            if res.status().is_server_error() {
+                if let Some(error) = res.response().error() {
+                    log::error!("{}", error);
+                }
+            }
+            if let Ok(value) = HeaderValue::from_str(&id.0) {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            Ok(res)
+        })
+        .await
+}
+
+/// The request's ID, for the access log format (see main.rs)
+pub fn access_log_id(req: &ServiceRequest) -> String {
+    req.extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+#[test]
+fn test_request_id() {
+    let id = RequestId::from_header(Some(&HeaderValue::from_static("abc-123_4.5")));
+    assert_eq!(id.0, "abc-123_4.5");
+    for unsafe_id in ["", "a b", "a\"b", &"a".repeat(MAX_REQUEST_ID_LENGTH + 1)] {
+        let id = RequestId::from_header(Some(&HeaderValue::from_str(unsafe_id).unwrap()));
+        assert_ne!(id.0, unsafe_id);
+        assert!(!id.0.is_empty());
+    }
+    assert!(!RequestId::from_header(None).0.is_empty());
+    assert_eq!(LogFormat::try_parse("JSON"), Some(LogFormat::Json));
+}
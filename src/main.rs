@@ -1,129 +1,377 @@
 use actix_web::rt::{self, time};
 use askama::Template;
 use db::DbPool;
-use poll::{Poll, PollData, PollID, PollType};
-use r2d2_sqlite::SqliteConnectionManager;
+use poll::{PageContext, Poll, PollData, PollID, PollSettings, PollType};
+use rate::Route;
 use serde::Deserialize;
 
 use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer, Result};
-use anyhow::{bail, Context};
-use std::time::Duration;
+use anyhow::Context;
+use futures::StreamExt;
+use std::path::Path;
 
 #[macro_use]
 mod util;
+mod config;
 mod db;
 mod error;
 use error::*;
 mod admin;
+mod backup;
+mod chart;
+mod cli;
+mod embed;
+mod export;
+mod live;
+mod logging;
+mod metrics;
 mod poll;
+mod pow;
+mod qr;
 mod rate;
+mod security;
+mod session;
+mod shutdown;
 mod templates;
+mod tls;
+mod webhook;
 
-/// The database path. Can be overridden by cmd arguments.
-const DB_PATH_DEFAULT: &str = "db/main.db";
-/// The IP address the server binds to. Can be overridden by cmd arguments.
-const BIND_ADDRESS_DEFAULT: &str = "0.0.0.0:8080";
-/// The time interval between each rate limit store cleanup (in seconds). Can be overridden
-/// by a "POLL_CLEANUP_INTERVAL" environmental variable.
-const CLEANUP_INTERVAL_DEFAULT: Duration = Duration::from_secs(30);
-/// The time interval that a single IP has to wait before creating a new poll (in seconds).
-/// Can be overridden by a "POLL_CREATE_LIMIT" environmental variable.
-const CREATE_LIMIT: Duration = Duration::from_secs(10 * 60);
-/// The time interval that a single IP has to wait before voting on a single poll.
-/// Can be overridden by a "POLL_VOTE_LIMIT" environmental variable.
-const VOTE_LIMIT: Duration = Duration::from_secs(30 * 60);
+/// The default access log format of actix-web, with the request ID at the end
+const ACCESS_LOG_FORMAT: &str =
+    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{request_id}xi"#;
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
-
-    let args: Vec<String> = std::env::args().collect();
-    if let Some("help") = args.get(1).map(String::as_ref) {
-        println!("USAGE: poll (DATABASE_PATH) (BIND_ADDRESS)");
-        println!("The default DATABASE_PATH is ./db/main.db");
-        println!("The default BIND_ADDRESS is 0.0.0.0:8080.");
-    }
-
-    let db_path = args.get(1).map(String::as_ref).unwrap_or(DB_PATH_DEFAULT);
-    let db_path = std::path::Path::new(db_path);
-    if !db_path.exists() {
-        bail!(
-            "Database file {:?} does not exist or could not be read.",
-            db_path
-        );
+    let args = cli::Args::parse_checked();
+    let mut config = config::Config::load(args.config.as_deref())?;
+    logging::init(config.log_format);
+    if let Some(database) = args.db {
+        config.database = database;
+    }
+
+    match args.command {
+        None => serve(config, args.serve).await,
+        Some(cli::Command::Serve(serve_args)) => serve(config, serve_args).await,
+        Some(cli::Command::CheckConfig) => {
+            config.check()?;
+            println!("Configuration OK.");
+            Ok(())
+        }
+        Some(command) => cli::run(command, &config).await,
+    }
+}
+
+/// Runs the server until it's stopped
+async fn serve(mut config: config::Config, args: cli::ServeArgs) -> anyhow::Result<()> {
+    if let Some(database) = args.database {
+        config.database = database;
+    }
+    if let Some(bind_address) = args.bind_address {
+        config.bind = vec![bind_address];
     }
 
-    // Get bind address
-    let bind_address = args
-        .get(2)
-        .map(String::as_ref)
-        .unwrap_or(BIND_ADDRESS_DEFAULT);
-    log::info!("Setting the bind address to: {}", bind_address);
+    log::info!("Setting the bind addresses to: {}", config.bind.join(", "));
+    // Certificates for serving HTTPS, reloaded on SIGHUP (see below)
+    let cert_store = config.cert_store()?.map(std::sync::Arc::new);
+    match &cert_store {
+        Some(_) => log::info!(
+            "Serving HTTPS on: {}, certificate {:?}.",
+            config.tls.bind.join(", "),
+            config.tls.cert.as_ref().unwrap()
+        ),
+        None => log::info!("TLS bind addresses not set - HTTPS off."),
+    }
 
-    // Read the admin token from environmental variables
-    let admin_token = std::env::var("POLL_ADMIN_TOKEN").ok();
-    if admin_token.is_none() {
-        log::warn!("Environment variable POLL_ADMIN_TOKEN not set - admin functions off.");
+    if config.admin_token.is_none() {
+        log::warn!("Admin token not set - admin functions off.");
     }
 
-    // Read other environmental variables
-    let cleanup_interval =
-        util::get_env_duration_or("POLL_CLEANUP_INTERVAL", CLEANUP_INTERVAL_DEFAULT)?;
     log::info!(
         "Setting the cleanup interval to {} seconds.",
-        cleanup_interval.as_secs()
+        config.cleanup_interval.as_secs()
     );
-    let create_limit = util::get_env_duration_or("POLL_CREATE_LIMIT", CREATE_LIMIT)?;
+    let policies = config.policies()?;
+    for (route, policy) in &policies {
+        log::info!("Setting the {:?} limit to: {}.", route, policy);
+    }
     log::info!(
-        "Setting the create limit to {} seconds.",
-        create_limit.as_secs()
+        "Rate limiting IPv4 networks by /{} and IPv6 networks by /{}.",
+        config.ipv4_prefix,
+        config.ipv6_prefix
     );
-    let vote_limit = util::get_env_duration_or("POLL_VOTE_LIMIT", VOTE_LIMIT)?;
     log::info!(
-        "Setting the vote limit to {} seconds.",
-        vote_limit.as_secs()
+        "Setting the admin session length to {} seconds.",
+        config.session_length.as_secs()
     );
+    let challenges = web::Data::new(pow::Challenges::new(config.pow()));
+    if challenges.is_enabled() {
+        log::info!(
+            "Setting the proof of work difficulty to {} bits.",
+            config.pow.difficulty
+        );
+    } else {
+        log::info!("Proof of work difficulty not set - proof of work off.");
+    }
+
+    // Read the reverse proxies allowed to report client addresses
+    let trusted_proxies = config.trusted_proxies()?;
+    if trusted_proxies.is_empty() {
+        log::info!("No trusted proxies set - using peer addresses for rate limiting.");
+    }
+    let trusted_proxies = web::Data::new(trusted_proxies);
+
+    if config.embed_ancestors.is_empty() {
+        log::info!("Embed ancestors not set - polls can only be embedded on this website.");
+    }
+    let embed_policy = web::Data::new(config.embed_policy()?);
+    let hub = web::Data::new(live::Hub::default());
+    let webhooks = web::Data::new(config.webhooks());
+    match webhooks.as_ref() {
+        Some(_) if config.webhooks.allow_private => {
+            log::warn!("Webhooks can be sent to private network addresses.")
+        }
+        Some(_) => {}
+        None => log::info!("Webhooks disabled."),
+    }
+    let metrics_access = web::Data::new(config.metrics_access());
+    match (metrics_access.as_ref(), &config.metrics.token) {
+        (Some(_), Some(_)) => log::info!("Serving metrics to scrapers with the metrics token."),
+        (Some(_), None) => log::warn!("Metrics token not set - metrics are public."),
+        (None, _) => log::info!("Metrics disabled."),
+    }
 
     // SQLite database connection
-    log::info!("Connecting to database: {} ...", db_path.to_str().unwrap());
-    let manager = SqliteConnectionManager::file(db_path);
-    let pool = DbPool::new(manager)?;
+    log::info!("Connecting to database: {:?} ...", config.database);
+    let metrics = web::Data::new(metrics::Metrics::default());
+    let pool = db::connect_with(&config.database, metrics::PoolEvents(metrics.clone()))?;
 
     log::info!("Connected to database!");
 
+    let applied = db::migrate(&pool).await?;
+    if applied > 0 {
+        log::info!("Applied {} database migrations.", applied);
+    }
+
+    let session_key = match config.session_key.clone() {
+        Some(key) => key,
+        None => {
+            log::warn!("Session key not set - using a key stored in the database.");
+            db::session_key(&pool, &session::SessionKey::generate()).await?
+        }
+    };
+    let sessions = web::Data::new(session::SessionConfig {
+        key: session::SessionKey::new(Some(session_key)),
+        length: config.session_length,
+    });
+
     // Create the rate limit store and a thread that periodically
     // checks and cleans up expired limits.
-    let limits = web::Data::new(rate::LimitStore::new(create_limit, vote_limit));
+    let limits = web::Data::new(rate::LimitStore::new(
+        policies,
+        config.ipv4_prefix,
+        config.ipv6_prefix,
+    )?);
+    let persist_limits = config.persist_limits;
+    if persist_limits {
+        let records = db::load_limits(&pool).await?;
+        log::info!("Loaded {} rate limits from the database.", records.len());
+        limits.import(records);
+    }
     let l = limits.clone();
-    rt::spawn(async move {
+    let c = challenges.clone();
+    let p = pool.clone();
+    let cleanup_interval = config.cleanup_interval;
+    let cleanup = rt::spawn(async move {
         let limits = l;
         let mut interval = time::interval(cleanup_interval);
         loop {
             interval.tick().await;
             limits.cleanup();
+            c.cleanup();
             log::debug!("Rate limits cleaned up");
+            // Limits are only written on every cleanup, so the last few seconds of them
+            // can be lost if the server crashes
+            if persist_limits {
+                if let Err(e) = db::save_limits(&p, &limits.export()).await {
+                    log::error!("Failed to save rate limits: {}", e);
+                }
+            }
         }
     });
 
-    HttpServer::new(move || {
+    // Scheduled backups, the first one is made after the first interval
+    let backups = web::Data::new(config.backups());
+    let scheduled_backups = match backups.as_ref() {
+        Some(_) if !config.backup.interval.is_zero() => {
+            log::info!(
+                "Backing up the database every {} seconds to {:?}, keeping {} backups.",
+                config.backup.interval.as_secs(),
+                config.backup.dir.as_ref().unwrap(),
+                config.backup.keep
+            );
+            let b = backups.clone();
+            let p = pool.clone();
+            let backup_interval = config.backup.interval;
+            Some(rt::spawn(async move {
+                let start = time::Instant::now() + backup_interval;
+                let mut interval = time::interval_at(start, backup_interval);
+                loop {
+                    interval.tick().await;
+                    match b.as_ref().as_ref().unwrap().create(&p).await {
+                        Ok(path) => log::info!("Backup saved to {:?}", path),
+                        Err(e) => log::error!("Failed to back up the database: {:#}", e),
+                    }
+                }
+            }))
+        }
+        Some(_) => {
+            log::info!("Backup interval set to 0 - scheduled backups off.");
+            None
+        }
+        None => {
+            log::info!("Backup directory not set - backups off.");
+            None
+        }
+    };
+
+    // Renewed certificates are picked up without restarting
+    #[cfg(unix)]
+    if let Some(store) = cert_store.clone() {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = signal(SignalKind::hangup())?;
+        rt::spawn(async move {
+            while hangups.recv().await.is_some() {
+                match store.reload() {
+                    Ok(()) => log::info!("TLS certificate reloaded."),
+                    Err(e) => log::error!("Failed to reload the TLS certificate: {:#}", e),
+                }
+            }
+        });
+    }
+
+    let shutdown = web::Data::new(shutdown::Shutdown::default());
+    let admin_token = config.admin_token.clone();
+    let static_dir = config.static_dir.clone();
+    let h = hub.clone();
+    let s = shutdown.clone();
+    // Used after the server stops, see below
+    let final_pool = pool.clone();
+    let final_limits = limits.clone();
+    let mut server = HttpServer::new(move || {
         App::new()
-            .wrap(middleware::Logger::default())
+            .wrap(middleware::from_fn(security::protect))
+            .wrap(middleware::from_fn(security::headers))
+            .wrap(
+                middleware::Logger::new(ACCESS_LOG_FORMAT)
+                    .custom_request_replace("request_id", logging::access_log_id),
+            )
+            .wrap(middleware::from_fn(metrics::record))
+            .wrap(middleware::from_fn(logging::request_id))
             .app_data(limits.clone())
+            .app_data(trusted_proxies.clone())
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::new(admin::AdminToken(admin_token.clone())))
-            .configure(app_config)
-    })
-    .bind(bind_address)?
-    .run()
-    .await
-    .context("An error occurred when running HttpServer")
+            .app_data(sessions.clone())
+            .app_data(challenges.clone())
+            .app_data(backups.clone())
+            .app_data(embed_policy.clone())
+            .app_data(hub.clone())
+            .app_data(webhooks.clone())
+            .app_data(metrics.clone())
+            .app_data(metrics_access.clone())
+            .app_data(shutdown.clone())
+            .configure(|c| app_config(c, &static_dir))
+    });
+    for address in &config.bind {
+        server = match address.strip_prefix(config::UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => {
+                remove_stale_socket(Path::new(path))?;
+                server.bind_uds(path)
+            }
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+            None => server.bind(address),
+        }
+        .with_context(|| format!("Failed to bind to {}", address))?;
+    }
+    if let Some(store) = &cert_store {
+        let tls = store.server_config()?;
+        for address in &config.tls.bind {
+            server = server
+                .bind_rustls_0_23(address, tls.clone())
+                .with_context(|| format!("Failed to bind to {}", address))?;
+        }
+    }
+    // Signals are handled below instead of by actix, to refuse votes and close the live
+    // results streams as soon as the shutdown starts
+    let server = server.disable_signals().run();
+    let handle = server.handle();
+    rt::spawn(async move {
+        match shutdown::signal().await {
+            Ok(signal) => log::info!("Received {}, shutting down...", signal),
+            Err(e) => {
+                log::error!("Failed to listen for shutdown signals: {}", e);
+                return;
+            }
+        }
+        s.begin();
+        h.close();
+        // Waits for the requests in progress to finish, unless asked to stop again
+        let graceful = Box::pin(handle.stop(true));
+        let again = Box::pin(shutdown::signal());
+        if let futures::future::Either::Right(_) = futures::future::select(graceful, again).await {
+            log::warn!("Received a second signal, stopping without waiting for requests.");
+            handle.stop(false).await;
+        }
+    });
+    server
+        .await
+        .context("An error occurred when running HttpServer")?;
+
+    // Every request has finished, write what's only kept in memory
+    cleanup.abort();
+    if let Some(backups) = scheduled_backups {
+        backups.abort();
+    }
+    if persist_limits {
+        match db::save_limits(&final_pool, &final_limits.export()).await {
+            Ok(()) => log::info!("Rate limits saved."),
+            Err(e) => log::error!("Failed to save rate limits: {}", e),
+        }
+    }
+    if let Err(e) = db::checkpoint(&final_pool).await {
+        log::error!("Failed to checkpoint the database: {}", e);
+    }
+    #[cfg(unix)]
+    for path in config
+        .bind
+        .iter()
+        .filter_map(|address| address.strip_prefix(config::UNIX_PREFIX))
+    {
+        let _ = remove_stale_socket(Path::new(path));
+    }
+    log::info!("Shutdown complete.");
+    Ok(())
+}
+
+/// Removes the Unix socket left behind by a previous run, so that it can be bound again
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{:?} exists and is not a socket", path);
+        }
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
 }
 
-fn app_config(config: &mut web::ServiceConfig) {
+fn app_config(config: &mut web::ServiceConfig, static_dir: &Path) {
     config
         .service(
-            actix_files::Files::new("/static", "static/")
+            actix_files::Files::new("/static", static_dir)
                 .prefer_utf8(true)
                 .index_file("index.html"),
         )
@@ -154,18 +402,53 @@ fn app_config(config: &mut web::ServiceConfig) {
                         .route(web::post().to(handle_vote_desc)),
                 )
                 .service(web::resource("/vote/{poll_id}/response").to(handle_vote_desc))
+                // QR code of the voting link
+                .service(web::resource("/vote/{poll_id}/qr.{format}").to(handle_vote_qr))
+                // Poll results as data, has to come before the results screen
+                .service(web::resource("/results/{poll_id}.{format}").to(handle_results_export))
+                // Live poll results
+                .service(web::resource("/results/{poll_id}/events").to(handle_results_events))
+                // Poll results as an SVG chart
+                .service(web::resource("/results/{poll_id}/chart.svg").to(handle_results_chart))
                 // Poll results screen
                 .service(
                     web::resource("/results/{poll_id}")
                         .name("results")
                         .to(handle_results),
                 )
+                // Widgets for embedding polls in other websites
+                .service(
+                    web::resource("/embed/vote/{poll_id}")
+                        .name("embed_vote")
+                        .route(web::get().to(embed::handle_embed_vote))
+                        .route(web::post().to(embed::handle_embed_vote_desc)),
+                )
+                .service(
+                    web::resource("/embed/results/{poll_id}")
+                        .name("embed_results")
+                        .to(embed::handle_embed_results),
+                )
+                .service(web::resource("/oembed").to(embed::handle_oembed))
+                // Monitoring
+                .service(web::resource("/metrics").to(metrics::handle_metrics))
+                .service(web::resource("/healthz").to(metrics::handle_healthz))
+                .service(web::resource("/readyz").to(metrics::handle_readyz))
+                // Proof of work challenges for scripts
+                .service(web::resource("/challenge/{route}").to(handle_challenge))
                 // General management callback
                 .service(
                     web::resource("/admin")
                         .route(web::get().to(admin::handle_admin))
                         .route(web::post().to(admin::handle_admin_action)),
                 )
+                // General management session
+                .service(
+                    web::resource("/admin/login").route(web::post().to(admin::handle_admin_login)),
+                )
+                .service(
+                    web::resource("/admin/logout")
+                        .route(web::post().to(admin::handle_admin_logout)),
+                )
                 // Poll management callback
                 .service(
                     web::resource("/admin/{poll_id}")
@@ -173,6 +456,26 @@ fn app_config(config: &mut web::ServiceConfig) {
                         .route(web::get().to(admin::handle_poll_admin))
                         .route(web::post().to(admin::handle_poll_admin_action)),
                 )
+                // Poll management session
+                .service(
+                    web::resource("/admin/{poll_id}/login")
+                        .route(web::post().to(admin::handle_poll_admin_login)),
+                )
+                .service(
+                    web::resource("/admin/{poll_id}/logout")
+                        .route(web::post().to(admin::handle_poll_admin_logout)),
+                )
+                // Importing ballots collected elsewhere
+                .service(
+                    web::resource("/admin/{poll_id}/import")
+                        .app_data(web::FormConfig::default().limit(admin::MAX_IMPORT_SIZE))
+                        .route(web::post().to(admin::handle_poll_import)),
+                )
+                // Adding and removing webhooks
+                .service(
+                    web::resource("/admin/{poll_id}/webhooks")
+                        .route(web::post().to(admin::handle_poll_webhooks)),
+                )
                 // 404 screen
                 .default_service(web::to(handle_default)),
         );
@@ -202,12 +505,22 @@ struct CreateParams {
 /// Params:
 ///  - poll_type: PollType enum variant, determines the poll creation website,
 ///    see PollType::try_parse for parsing format
-async fn handle_create(params: web::Query<CreateParams>) -> Result<HttpResponse> {
+async fn handle_create(
+    params: web::Query<CreateParams>,
+    csrf: security::CsrfToken,
+    challenges: web::Data<pow::Challenges>,
+    sessions: web::Data<session::SessionConfig>,
+) -> Result<HttpResponse> {
     // If there is a poll type specified
     if let Some(poll_type) = params.poll_type.as_ref() {
         let poll_type = PollType::try_parse(poll_type)?;
+        let challenge = challenges.issue(&sessions.key, Route::Create);
         let content = poll_type
-            .creation_site()
+            .creation_site(&PageContext {
+                csrf_token: &csrf.0,
+                pow_challenge: challenge.as_deref(),
+                embed: false,
+            })
             .map_err(|e| UserError::InternalError(e.into()))?;
 
         return_html!(content)
@@ -221,6 +534,10 @@ struct CreateDescParams {
     name: String,
     r#type: String,
     data: String,
+    #[serde(default)]
+    dedupe: bool,
+    pow_challenge: Option<String>,
+    pow_solution: Option<String>,
 }
 
 /// Handles complete poll creation requests
@@ -229,21 +546,33 @@ struct CreateDescParams {
 ///  - type: PollType enum variant, see PollType::try_parse for parsing format
 ///  - data: To be parsed as a PollFormat trait object, see the corresponding
 ///    PollType::from_data function for the proper format
+///  - dedupe: (optional) Refuse repeat votes from the same browser (bool)
+///  - pow_challenge, pow_solution: A solved proof of work challenge, required if proof of
+///    work is turned on, see pow::Challenges
 async fn handle_create_desc(
     req: HttpRequest,
     params: web::Form<CreateDescParams>,
     db: web::Data<DbPool>,
+    challenges: web::Data<pow::Challenges>,
+    sessions: web::Data<session::SessionConfig>,
+    metrics: web::Data<metrics::Metrics>,
 ) -> Result<HttpResponse> {
+    let solved = challenges.verify(
+        &sessions.key,
+        Route::Create,
+        params.pow_challenge.as_deref(),
+        params.pow_solution.as_deref(),
+    )?;
     // Check for rate limiting of poll creation for given IP
-    if rate::limit_create(&req) {
-        return Err(UserError::TooManyRequests.into());
-    }
+    rate::limit(&req, Route::Create, None)?;
 
     let name = &params.name;
     let ptype = PollType::try_parse(&params.r#type)?;
     let data = params.data.as_str();
 
     let format = poll::create_poll_format_from_data(ptype, data)?;
+    // The challenge is only used up by valid requests
+    challenges.redeem(solved)?;
 
     // Generate poll ID
     let id = db::last_id(&db).await? + 1;
@@ -259,12 +588,16 @@ async fn handle_create_desc(
             date_created: chrono::Utc::now(),
             admin_link: admin_token.clone(),
             voters: 0,
+            settings: PollSettings {
+                dedupe: params.dedupe,
+            },
         },
         format,
     };
 
     log::info!("Inserting poll id: {} to database...", id);
     db::insert_poll(&db, poll).await?;
+    metrics.poll_created(ptype);
 
     let content = templates::PollCreatedTemplate {
         name,
@@ -280,57 +613,215 @@ async fn handle_create_desc(
 }
 
 /// Handles the voting webpage
-async fn handle_vote(db: web::Data<DbPool>, poll_id: web::Path<String>) -> Result<HttpResponse> {
-    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+/// Browsers that have already voted on a poll with duplicate vote detection
+/// are shown a link to the results instead.
+async fn handle_vote(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+    csrf: security::CsrfToken,
+    sessions: web::Data<session::SessionConfig>,
+    challenges: web::Data<pow::Challenges>,
+) -> Result<HttpResponse> {
+    let content = vote_page(&req, &db, &poll_id, &csrf, &sessions, &challenges, false).await?;
+    return_html!(content)
+}
 
-    let poll = db::get_poll(&db, poll_id).await?;
+/// Renders the voting webpage, or the voting widget if `embed` is set (see embed.rs)
+async fn vote_page(
+    req: &HttpRequest,
+    db: &DbPool,
+    poll_id: &str,
+    csrf: &security::CsrfToken,
+    sessions: &session::SessionConfig,
+    challenges: &pow::Challenges,
+    embed: bool,
+) -> Result<String> {
+    let poll_id: PollID = PollID::try_from(poll_id)?;
+
+    let poll = db::get_poll(db, poll_id).await?;
+    if poll.data.settings.dedupe && session::VoterCookie::has_voted(req, &sessions.key, poll_id) {
+        return voted_page(req, poll_id, embed);
+    }
     let content = poll
         .format
-        .voting_site(&poll.data)
+        .voting_site(
+            &poll.data,
+            &PageContext {
+                csrf_token: &csrf.0,
+                pow_challenge: challenges.issue(&sessions.key, Route::Vote).as_deref(),
+                embed,
+            },
+        )
         .map_err(|e| UserError::InternalError(e.into()))?;
 
-    return_html!(content)
+    Ok(content)
+}
+
+/// Handles the QR code of a poll's voting link: `/vote/{poll_id}/qr.{format}`
+/// Formats:
+///  - svg: a scalable image, for printing
+///  - png: an 8 pixels per module image, for slides and chats
+async fn handle_vote_qr(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    let (poll_id, format) = path.into_inner();
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    if !matches!(format.as_str(), "svg" | "png") {
+        return handle_default().await;
+    }
+    rate::limit(&req, Route::Results, None)?;
+
+    // Only for existing polls
+    db::get_poll(&db, poll_id).await?;
+    let link = req.url_for("vote", [poll_id.to_string()]).unwrap();
+
+    let mut response = HttpResponse::Ok();
+    let response = match format.as_str() {
+        "svg" => response
+            .content_type("image/svg+xml")
+            .body(qr::svg(link.as_str()).map_err(UserError::InternalError)?),
+        _ => response
+            .content_type("image/png")
+            .body(qr::png(link.as_str()).map_err(UserError::InternalError)?),
+    };
+    Ok(response)
 }
 
 /// Handles the voting callback
 /// Params:
 ///  - params: PollFormat-specific vote information, see the corresponding
 ///    PollFormat::register_votes function for the proper format
+///  - pow_challenge, pow_solution: A solved proof of work challenge, required if proof of
+///    work is turned on, see pow::Challenges
 async fn handle_vote_desc(
     req: HttpRequest,
     db: web::Data<DbPool>,
     poll_id: web::Path<String>,
     params: String,
+    sessions: web::Data<session::SessionConfig>,
+    challenges: web::Data<pow::Challenges>,
 ) -> Result<HttpResponse> {
-    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
-    if rate::limit_vote(&req, poll_id) {
-        return Err(UserError::TooManyRequests.into());
+    cast_vote(&req, &db, &poll_id, &params, &sessions, &challenges, None).await
+}
+
+/// Registers a vote and returns the "voted" page,
+/// or the voting widget's version of it if `embed` is set (see embed.rs)
+async fn cast_vote(
+    req: &HttpRequest,
+    db: &DbPool,
+    poll_id: &str,
+    params: &str,
+    sessions: &session::SessionConfig,
+    challenges: &pow::Challenges,
+    embed: Option<&security::EmbedPolicy>,
+) -> Result<HttpResponse> {
+    let poll_id: PollID = PollID::try_from(poll_id)?;
+    // Votes still in progress are finished, but no new ones are started
+    if req
+        .app_data::<web::Data<shutdown::Shutdown>>()
+        .expect("Shutdown not registered")
+        .is_started()
+    {
+        return Err(UserError::ShuttingDown.into());
     }
 
-    let mut poll = db::get_poll(&db, poll_id).await?;
+    let (challenge, params) = util::take_form_field(params, "pow_challenge");
+    let (solution, params) = util::take_form_field(&params, "pow_solution");
+    let solved = challenges.verify(&sessions.key, Route::Vote, challenge, solution)?;
+    rate::limit(req, Route::Vote, Some(poll_id))?;
+
+    let mut poll = db::get_poll(db, poll_id).await?;
+    let dedupe = poll.data.settings.dedupe;
+    if dedupe && session::VoterCookie::has_voted(req, &sessions.key, poll_id) {
+        return Err(UserError::AlreadyVoted.into());
+    }
 
     poll.format
         .register_votes(params.as_str())
         .map_err(UserError::Voting)?;
+    // The challenge is only used up by valid votes
+    challenges.redeem(solved)?;
     poll.data.voters += 1;
+    let ballot = poll.format.ballot(params.as_str());
+
+    db::record_vote(db, &poll, ballot.as_ref()).await?;
+    req.app_data::<web::Data<live::Hub>>()
+        .expect("Hub not registered")
+        .publish(&poll);
+    req.app_data::<web::Data<metrics::Metrics>>()
+        .expect("Metrics not registered")
+        .vote(poll.data.ptype);
+    if let Some(webhooks) = req
+        .app_data::<web::Data<Option<webhook::Webhooks>>>()
+        .and_then(|webhooks| webhooks.as_ref().as_ref())
+    {
+        webhooks.notify(db, &poll, webhook::Event::Vote).await;
+    }
 
-    db::update_poll(&db, &poll).await?;
+    let content = voted_page(req, poll_id, embed.is_some())?;
 
+    let mut response = match embed {
+        Some(policy) => policy.response(),
+        None => {
+            let mut response = HttpResponse::Ok();
+            response.content_type("text/html; charset=utf-8");
+            response
+        }
+    };
+    if dedupe {
+        response.cookie(session::VoterCookie::cookie(req, &sessions.key, poll_id));
+    }
+    Ok(response.body(content))
+}
+
+/// The page shown after voting, with a link to the results
+fn voted_page(req: &HttpRequest, poll_id: PollID, embed: bool) -> Result<String> {
+    let results = if embed { "embed_results" } else { "results" };
     let content = templates::VotedTemplate {
         results_link: req
-            .url_for("results", [poll_id.to_string()])
+            .url_for(results, [poll_id.to_string()])
             .unwrap()
             .as_str(),
+        embed,
     }
     .render()
     .map_err(|e| UserError::InternalError(e.into()))?;
+    Ok(content)
+}
 
-    return_html!(content)
+/// Returns a new proof of work challenge for scripts, in plain text.
+/// Returns 204 No Content if proof of work is turned off.
+/// Params:
+///  - route: "create" or "vote", the route the challenge will be submitted to
+async fn handle_challenge(
+    route: web::Path<String>,
+    challenges: web::Data<pow::Challenges>,
+    sessions: web::Data<session::SessionConfig>,
+) -> Result<HttpResponse> {
+    let route = match route.as_str() {
+        "create" => Route::Create,
+        "vote" => Route::Vote,
+        _ => return handle_default().await,
+    };
+    match challenges.issue(&sessions.key, route) {
+        Some(challenge) => Ok(HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(challenge)),
+        None => Ok(HttpResponse::NoContent().finish()),
+    }
 }
 
 /// Handles the results website
-async fn handle_results(db: web::Data<DbPool>, poll_id: web::Path<String>) -> Result<HttpResponse> {
+async fn handle_results(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+) -> Result<HttpResponse> {
     let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    rate::limit(&req, Route::Results, None)?;
 
     let poll = db::get_poll(&db, poll_id).await?;
 
@@ -341,3 +832,391 @@ async fn handle_results(db: web::Data<DbPool>, poll_id: web::Path<String>) -> Re
 
     return_html!(content)
 }
+
+/// Handles the live results of a poll: a Server-Sent Events stream with a "results"
+/// event with the current results right away and after every vote (see live::Hub).
+/// The events have the same data as `/results/{poll_id}.json`, so the stream is
+/// as public as the results page.
+async fn handle_results_events(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+    hub: web::Data<live::Hub>,
+) -> Result<HttpResponse> {
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    rate::limit(&req, Route::Results, None)?;
+
+    // Subscribe before reading the poll, so that a vote in between isn't missed
+    let events = match hub.subscribe(poll_id, rate::client_key(&req)) {
+        Some(events) => events,
+        None => return Ok(HttpResponse::ServiceUnavailable().finish()),
+    };
+    let poll = db::get_poll(&db, poll_id).await?;
+    // Comments keep proxies from closing idle connections. The stream ends when the hub
+    // drops its end of the channel (see live::Hub::close).
+    let interval = time::interval_at(time::Instant::now() + live::KEEP_ALIVE, live::KEEP_ALIVE);
+    let updates = futures::stream::unfold(
+        (events, interval),
+        |(mut events, mut interval)| async move {
+            let tick = Box::pin(interval.tick());
+            let bytes = match futures::future::select(events.next(), tick).await {
+                futures::future::Either::Left((event, _)) => event?,
+                futures::future::Either::Right(_) => web::Bytes::from_static(b": keep-alive\n\n"),
+            };
+            Some((bytes, (events, interval)))
+        },
+    );
+    let stream = futures::stream::once(futures::future::ready(live::event(&poll)))
+        .chain(updates)
+        .map(Ok::<_, actix_web::Error>);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((actix_web::http::header::CACHE_CONTROL, "no-cache"))
+        // Stops nginx from buffering the events
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(stream))
+}
+
+#[derive(Deserialize)]
+struct ChartParams {
+    kind: Option<String>,
+}
+
+/// Handles the results as an SVG chart: `/results/{poll_id}/chart.svg`
+/// Params:
+///  - kind: "bar" (default), "pie" or "stacked". Stacked charts show the places
+///    voters gave every option, so they're only available for ranked polls.
+async fn handle_results_chart(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    poll_id: web::Path<String>,
+    params: web::Query<ChartParams>,
+) -> Result<HttpResponse> {
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    let kind = match params.kind.as_deref() {
+        None => chart::ChartKind::Bar,
+        Some(kind) => match chart::ChartKind::try_parse(kind) {
+            Some(kind) => kind,
+            None => return handle_default().await,
+        },
+    };
+    rate::limit(&req, Route::Results, None)?;
+
+    let poll = db::get_poll(&db, poll_id).await?;
+    let state = poll.format.export_state();
+    let svg = match kind {
+        chart::ChartKind::Bar => chart::bar(&poll.data.name, &state),
+        chart::ChartKind::Pie => chart::pie(&poll.data.name, &state),
+        chart::ChartKind::Stacked if poll.data.ptype.has_ballots() => {
+            let ballots = complete_ballots(&db, &poll).await?;
+            chart::stacked(&poll.data.name, &state, &ballots)
+        }
+        chart::ChartKind::Stacked => return handle_default().await,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/svg+xml")
+        .insert_header((actix_web::http::header::CACHE_CONTROL, "no-cache"))
+        .body(svg))
+}
+
+/// Returns the stored ballots of a poll. Polls with votes cast before ballots were stored
+/// are refused, since their ballots wouldn't add up to the tallies.
+async fn complete_ballots(db: &DbPool, poll: &Poll) -> Result<Vec<poll::Ballot>> {
+    let ballots = db::get_ballots(db, poll.data.id).await?;
+    // Votes cast after the poll was read can only add ballots
+    if (ballots.len() as u64) < poll.data.voters {
+        return Err(UserError::MissingBallots(ballots.len() as u64, poll.data.voters).into());
+    }
+    Ok(ballots)
+}
+
+/// Handles the results in a machine-readable format: `/results/{poll_id}.{format}`.
+/// Formats:
+///  - csv: a row with each option and its tally
+///  - json: the tallies and the poll's metadata (see export::PollResults)
+///  - blt, abif, preflib: the ballots of ranked polls for external election tools
+///    (see poll::BallotExport). PrefLib files are served as ".soc" or ".toc".
+///    Refused with 409 if some of the ballots weren't stored, see complete_ballots.
+///
+/// Options are in the order they were created in.
+async fn handle_results_export(
+    req: HttpRequest,
+    db: web::Data<DbPool>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    let (poll_id, format) = path.into_inner();
+    let poll_id: PollID = PollID::try_from(poll_id.as_str())?;
+    if !matches!(format.as_str(), "csv" | "json" | "blt" | "abif" | "preflib") {
+        return handle_default().await;
+    }
+    rate::limit(&req, Route::Results, None)?;
+
+    let poll = db::get_poll(&db, poll_id).await?;
+    let results = export::PollResults::new(&poll);
+
+    let (extension, content_type, body) = match format.as_str() {
+        "csv" => ("csv", "text/csv; charset=utf-8", results.to_csv()),
+        "json" => (
+            "json",
+            "application/json",
+            serde_json::to_string(&results).map_err(|e| UserError::InternalError(e.into()))?,
+        ),
+        _ if !poll.data.ptype.has_ballots() => return handle_default().await,
+        ballot_format => {
+            let ballots = complete_ballots(&db, &poll).await?;
+            let options = results
+                .state
+                .options
+                .iter()
+                .map(|option| option.name.as_str())
+                .collect();
+            let export = poll::BallotExport::new(&poll.data.name, options, &ballots);
+            match ballot_format {
+                "blt" => ("blt", "text/plain; charset=utf-8", export.blt()),
+                "abif" => ("abif", "text/plain; charset=utf-8", export.abif()),
+                _ => {
+                    let (data_type, preflib) = export.preflib();
+                    (data_type, "text/plain; charset=utf-8", preflib)
+                }
+            }
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            actix_web::http::header::CONTENT_DISPOSITION,
+            format!(
+                "inline; filename=\"results-{}.{}\"",
+                poll_id.index(),
+                extension
+            ),
+        ))
+        .content_type(content_type)
+        .body(body))
+}
+
+#[actix_web::test]
+async fn test_dedupe_across_routes() {
+    use actix_web::test;
+
+    let dir = std::env::temp_dir().join(format!("pollinator-dedupe-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let pool = db::init(&dir.join("main.db")).await.unwrap();
+    let id = PollID::generate(1);
+    let poll = Poll {
+        data: PollData {
+            id,
+            ptype: PollType::Single,
+            name: "Dedupe".to_string(),
+            date_created: chrono::Utc::now(),
+            admin_link: "token".to_string(),
+            voters: 0,
+            settings: PollSettings { dedupe: true },
+        },
+        format: poll::create_poll_format_from_data(PollType::Single, "A,B").unwrap(),
+    };
+    db::insert_poll(&pool, poll).await.unwrap();
+
+    let config = config::Config::default();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(session::SessionConfig {
+                key: session::SessionKey::new(None),
+                length: config.session_length,
+            }))
+            .app_data(web::Data::new(pow::Challenges::new(config.pow())))
+            .app_data(web::Data::new(config.embed_policy().unwrap()))
+            .app_data(web::Data::new(live::Hub::default()))
+            .app_data(web::Data::new(metrics::Metrics::default()))
+            .app_data(web::Data::new(shutdown::Shutdown::default()))
+            .configure(|c| app_config(c, Path::new("static/"))),
+    )
+    .await;
+
+    // Direct local requests, which aren't rate limited
+    let vote = |path: String| {
+        test::TestRequest::post()
+            .uri(&path)
+            .peer_addr("127.0.0.1:8000".parse().unwrap())
+            .insert_header((
+                actix_web::http::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            ))
+            .set_payload("response=0")
+    };
+    let res = test::call_service(&app, vote(format!("/vote/{}", id)).to_request()).await;
+    assert!(res.status().is_success());
+    let cookie = res.response().cookies().next().unwrap().into_owned();
+    assert_eq!(cookie.path(), Some("/"));
+
+    // The cookie set by the voting page also applies to the voting widget
+    let res = test::call_service(
+        &app,
+        vote(format!("/embed/vote/{}", id))
+            .cookie(cookie)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), actix_web::http::StatusCode::FORBIDDEN);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[actix_web::test]
+async fn test_embed_vote_cross_site() {
+    use actix_web::cookie::{Cookie, SameSite};
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test;
+
+    let dir = std::env::temp_dir().join(format!("pollinator-embed-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let pool = db::init(&dir.join("main.db")).await.unwrap();
+    let id = PollID::generate(1);
+    let poll = Poll {
+        data: PollData {
+            id,
+            ptype: PollType::Single,
+            name: "Embedded".to_string(),
+            date_created: chrono::Utc::now(),
+            admin_link: "token".to_string(),
+            voters: 0,
+            settings: PollSettings { dedupe: true },
+        },
+        format: poll::create_poll_format_from_data(PollType::Single, "A,B").unwrap(),
+    };
+    db::insert_poll(&pool, poll).await.unwrap();
+
+    let config = config::Config::default();
+    let app = test::init_service(
+        App::new()
+            .wrap(middleware::from_fn(security::protect))
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(session::SessionConfig {
+                key: session::SessionKey::new(None),
+                length: config.session_length,
+            }))
+            .app_data(web::Data::new(pow::Challenges::new(config.pow())))
+            .app_data(web::Data::new(config.embed_policy().unwrap()))
+            .app_data(web::Data::new(live::Hub::default()))
+            .app_data(web::Data::new(metrics::Metrics::default()))
+            .app_data(web::Data::new(shutdown::Shutdown::default()))
+            .configure(|c| app_config(c, Path::new("static/"))),
+    )
+    .await;
+    // A widget framed by another website, served over HTTPS by a reverse proxy. The browser
+    // only sends the cookies marked SameSite=None with its requests.
+    let request = |req: test::TestRequest| {
+        req.uri(&format!("/embed/vote/{}", id))
+            .peer_addr("127.0.0.1:8000".parse().unwrap())
+            .insert_header(("X-Forwarded-Proto", "https"))
+    };
+    let cross_site = |cookies: Vec<Cookie<'static>>| {
+        cookies
+            .into_iter()
+            .filter(|cookie| cookie.same_site() == Some(SameSite::None))
+            .inspect(|cookie| assert_eq!(cookie.secure(), Some(true)))
+            .collect::<Vec<_>>()
+    };
+
+    let res = test::call_service(&app, request(test::TestRequest::get()).to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let cookies = cross_site(res.response().cookies().map(|c| c.into_owned()).collect());
+    assert_eq!(cookies.len(), 1);
+    let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    let token = body
+        .split("name=\"csrf_token\" value=\"")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap()
+        .to_string();
+
+    let vote = |cookies: &[Cookie<'static>], token: &str| {
+        let mut req = request(test::TestRequest::post())
+            .insert_header((header::CONTENT_TYPE, "application/x-www-form-urlencoded"))
+            .set_payload(format!("csrf_token={}&response=0", token));
+        for cookie in cookies {
+            req = req.cookie(cookie.clone());
+        }
+        req.to_request()
+    };
+    // Without the widget's cookie the token isn't accepted
+    let res = test::call_service(&app, vote(&[], &token)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = test::call_service(&app, vote(&cookies, &token)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let mut cookies = cookies;
+    cookies.extend(cross_site(
+        res.response().cookies().map(|c| c.into_owned()).collect(),
+    ));
+    assert_eq!(cookies.len(), 2);
+
+    // The voter cookie comes back from the framed widget, so the second vote is refused
+    let res = test::call_service(&app, vote(&cookies, &token)).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(body.contains("already voted"), "{}", body);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[actix_web::test]
+async fn test_missing_ballots() {
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+
+    let dir = std::env::temp_dir().join(format!("pollinator-ballots-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let pool = db::init(&dir.join("main.db")).await.unwrap();
+    let ptype = PollType::try_parse("RankedBorda").unwrap();
+    let mut format = poll::create_poll_format_from_data(ptype, "A,B").unwrap();
+    // A vote counted before ballots were stored, then one with its ballot
+    format.register_votes("0=0&1=1").unwrap();
+    format.register_votes("0=1&1=0").unwrap();
+    let ballot = format.ballot("0=1&1=0").unwrap();
+    let poll = Poll {
+        data: PollData {
+            id: PollID::generate(1),
+            ptype,
+            name: "Old".to_string(),
+            date_created: chrono::Utc::now(),
+            admin_link: "token".to_string(),
+            voters: 2,
+            settings: PollSettings::default(),
+        },
+        format,
+    };
+    let id = db::import_polls(&pool, &[(poll, vec![ballot])])
+        .await
+        .unwrap()[0];
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool))
+            .configure(|c| app_config(c, Path::new("static/"))),
+    )
+    .await;
+    let get = |path: String| {
+        test::TestRequest::get()
+            .uri(&path)
+            .peer_addr("127.0.0.1:8000".parse().unwrap())
+            .to_request()
+    };
+    for path in [
+        format!("/results/{}.blt", id),
+        format!("/results/{}.abif", id),
+        format!("/results/{}.preflib", id),
+        format!("/results/{}/chart.svg?kind=stacked", id),
+    ] {
+        let res = test::call_service(&app, get(path.clone())).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT, "{}", path);
+    }
+    // The tallies are still available
+    let res = test::call_service(&app, get(format!("/results/{}.csv", id))).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,334 @@
+//! Prometheus metrics (/metrics) and health checks (/healthz, /readyz) for process
+//! supervisors and monitoring. Metrics are kept in memory and reset on restart.
+//! The metrics endpoint is only served when turned on, see MetricsAccess.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+
+use crate::db::{self, DbPool};
+use crate::live;
+use crate::poll::PollType;
+use crate::rate::Route;
+use crate::security;
+use crate::shutdown::Shutdown;
+
+/// The longest time health checks wait for a database connection
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+/// Upper bounds of the request latency histogram buckets (in seconds)
+const REQUEST_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Upper bounds of the database connection wait histogram buckets (in seconds)
+const POOL_WAIT_BUCKETS: &[f64] = &[0.0001, 0.001, 0.01, 0.1, 1.0, 10.0];
+
+/// Counters of the server's activity, see Metrics::render for their names
+#[derive(Debug)]
+pub struct Metrics {
+    polls_created: Counters,
+    votes: Counters,
+    rate_limited: Counters,
+    pool_wait: Histogram,
+    pool_timeouts: AtomicU64,
+    requests: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            polls_created: Counters::default(),
+            votes: Counters::default(),
+            rate_limited: Counters::default(),
+            pool_wait: Histogram::new(POOL_WAIT_BUCKETS),
+            pool_timeouts: AtomicU64::new(0),
+            requests: Histogram::new(REQUEST_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn poll_created(&self, ptype: PollType) {
+        self.polls_created.increment(ptype.to_string());
+    }
+
+    pub fn vote(&self, ptype: PollType) {
+        self.votes.increment(ptype.to_string());
+    }
+
+    pub fn rate_limited(&self, route: Route) {
+        self.rate_limited.increment(route.to_string());
+    }
+
+    /// Writes the metrics in the Prometheus text format
+    pub fn render(&self, pool: &DbPool, hub: &live::Hub) -> String {
+        let mut out = String::new();
+        self.polls_created.render(
+            &mut out,
+            "pollinator_polls_created_total",
+            "Polls created since the server started",
+            "type",
+        );
+        self.votes.render(
+            &mut out,
+            "pollinator_votes_total",
+            "Votes cast since the server started",
+            "type",
+        );
+        self.rate_limited.render(
+            &mut out,
+            "pollinator_rate_limited_total",
+            "Requests rejected by rate limits",
+            "route",
+        );
+        self.requests.render(
+            &mut out,
+            "pollinator_request_duration_seconds",
+            "Time until the response headers were sent",
+        );
+        self.pool_wait.render(
+            &mut out,
+            "pollinator_db_pool_wait_seconds",
+            "Time spent waiting for a database connection",
+        );
+        let state = pool.state();
+        for (name, kind, help, value) in [
+            (
+                "pollinator_db_pool_timeouts_total",
+                "counter",
+                "Requests that gave up waiting for a database connection",
+                self.pool_timeouts.load(Ordering::Relaxed),
+            ),
+            (
+                "pollinator_db_pool_connections",
+                "gauge",
+                "Open database connections",
+                state.connections as u64,
+            ),
+            (
+                "pollinator_db_pool_idle_connections",
+                "gauge",
+                "Open database connections not in use",
+                state.idle_connections as u64,
+            ),
+            (
+                "pollinator_live_subscribers",
+                "gauge",
+                "Open live results streams",
+                hub.subscribers() as u64,
+            ),
+        ] {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+/// Counters with a single label
+#[derive(Debug, Default)]
+struct Counters(Mutex<BTreeMap<String, u64>>);
+
+impl Counters {
+    fn increment(&self, label: String) {
+        *self.0.lock().unwrap().entry(label).or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str, label: &str) {
+        header(out, name, "counter", help);
+        for (value, count) in self.0.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// The number of observations in each bucket (not cumulative), the last one is +Inf
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, "histogram", help);
+        let mut count = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = match self.bounds.get(index) {
+                Some(bound) => writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count),
+                None => writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count),
+            };
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Records connection checkouts of the database pool, see db::connect_with
+#[derive(Debug)]
+pub struct PoolEvents(pub web::Data<Metrics>);
+
+impl r2d2::HandleEvent for PoolEvents {
+    fn handle_checkout(&self, event: r2d2::event::CheckoutEvent) {
+        self.0.pool_wait.observe(event.duration());
+    }
+
+    fn handle_timeout(&self, _event: r2d2::event::TimeoutEvent) {
+        self.0.pool_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Middleware recording the latency of every request
+pub async fn record(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let start = Instant::now();
+    let metrics = req
+        .app_data::<web::Data<Metrics>>()
+        .expect("Metrics not registered")
+        .clone();
+    let response = next.call(req).await;
+    metrics.requests.observe(start.elapsed());
+    response
+}
+
+/// Who can read /metrics: anyone, or only scrapers sending the token
+/// in an "Authorization: Bearer" header
+pub struct MetricsAccess {
+    token: Option<String>,
+}
+
+impl MetricsAccess {
+    pub fn new(token: Option<String>) -> Self {
+        MetricsAccess { token }
+    }
+
+    /// Compares the bearer token of the request to the metrics token (in constant time)
+    pub fn allows(&self, bearer: Option<&str>) -> bool {
+        match (&self.token, bearer) {
+            (None, _) => true,
+            (Some(token), Some(bearer)) => bool::from(bearer.as_bytes().ct_eq(token.as_bytes())),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Handles the metrics in the Prometheus text format.
+/// Responds with 404 if metrics are off and with 401 without the right token.
+pub async fn handle_metrics(
+    req: HttpRequest,
+    access: web::Data<Option<MetricsAccess>>,
+    metrics: web::Data<Metrics>,
+    db: web::Data<DbPool>,
+    hub: web::Data<live::Hub>,
+) -> HttpResponse {
+    let Some(access) = access.as_ref() else {
+        return HttpResponse::NotFound().finish();
+    };
+    if !access.allows(security::bearer_token(&req)) {
+        return HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics.render(&db, &hub))
+}
+
+/// Liveness check: the server responds and can get a database connection
+pub async fn handle_healthz(db: web::Data<DbPool>) -> HttpResponse {
+    match db::ping(&db, HEALTH_TIMEOUT).await {
+        Ok(_) => HttpResponse::Ok().body("OK"),
+        Err(e) => {
+            log::error!("Health check failed: {}", e);
+            HttpResponse::ServiceUnavailable().body("Database unavailable")
+        }
+    }
+}
+
+/// Readiness check: the database is reachable, its schema is up to date and the server
+/// isn't shutting down
+pub async fn handle_readyz(db: web::Data<DbPool>, shutdown: web::Data<Shutdown>) -> HttpResponse {
+    if shutdown.is_started() {
+        return HttpResponse::ServiceUnavailable().body("Shutting down");
+    }
+    match db::ping(&db, HEALTH_TIMEOUT).await {
+        Ok(version) if version == db::SCHEMA_VERSION => HttpResponse::Ok().body("OK"),
+        Ok(_) => HttpResponse::ServiceUnavailable().body("Database schema out of date"),
+        Err(e) => {
+            log::error!("Readiness check failed: {}", e);
+            HttpResponse::ServiceUnavailable().body("Database unavailable")
+        }
+    }
+}
+
+#[test]
+fn test_metrics_access() {
+    let open = MetricsAccess::new(None);
+    assert!(open.allows(None));
+    assert!(open.allows(Some("anything")));
+    let protected = MetricsAccess::new(Some("secret".to_string()));
+    assert!(protected.allows(Some("secret")));
+    assert!(!protected.allows(Some("secreT")));
+    assert!(!protected.allows(Some("")));
+    assert!(!protected.allows(None));
+}
+
+#[test]
+fn test_metrics() {
+    let histogram = Histogram::new(&[0.1, 1.0]);
+    histogram.observe(Duration::from_millis(50));
+    histogram.observe(Duration::from_millis(500));
+    histogram.observe(Duration::from_secs(5));
+    let mut out = String::new();
+    histogram.render(&mut out, "test", "Test");
+    assert!(out.contains("test_bucket{le=\"0.1\"} 1\n"));
+    assert!(out.contains("test_bucket{le=\"1\"} 2\n"));
+    assert!(out.contains("test_bucket{le=\"+Inf\"} 3\n"));
+    assert!(out.contains("test_sum 5.55\n"));
+    assert!(out.contains("test_count 3\n"));
+
+    let counters = Counters::default();
+    counters.increment("Single".to_string());
+    counters.increment("Single".to_string());
+    counters.increment("RankedBorda".to_string());
+    let mut out = String::new();
+    counters.render(&mut out, "votes", "Votes", "type");
+    assert!(out.ends_with("votes{type=\"RankedBorda\"} 1\nvotes{type=\"Single\"} 2\n"));
+}
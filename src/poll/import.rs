@@ -0,0 +1,248 @@
+use anyhow::{anyhow, Context};
+
+use super::{Ballot, Poll, PollType};
+
+/// The most ballots a single file can add to a poll, including ballot weights
+pub const MAX_IMPORTED_BALLOTS: u64 = 100_000;
+
+/// Files of ballots collected outside the server (on paper or in other tools)
+#[derive(Debug, Clone, Copy)]
+pub enum BallotFormat {
+    /// One ballot per row, for single choice, multiple choice and score polls:
+    ///  - Single: the name of the chosen option
+    ///  - Multiple: the names of the chosen options
+    ///  - Score: a header row with the names of the options, then the points given to them
+    Csv,
+    /// The BLT format of STV counting tools, for ranked polls
+    Blt,
+    /// The Aggregated Ballot Information Format, for ranked polls
+    Abif,
+}
+
+impl BallotFormat {
+    pub fn try_parse(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "csv" => Ok(BallotFormat::Csv),
+            "blt" => Ok(BallotFormat::Blt),
+            "abif" => Ok(BallotFormat::Abif),
+            _ => Err(anyhow!("Unknown ballot format: {}", s)),
+        }
+    }
+}
+
+/// Reads the ballots of a file as vote requests of the poll's format (see
+/// PollFormat::register_votes), so that they are validated the same way as votes.
+pub fn read_votes(
+    ptype: PollType,
+    options: &[&str],
+    format: BallotFormat,
+    data: &str,
+) -> anyhow::Result<Vec<String>> {
+    let option = |name: &str| {
+        options
+            .iter()
+            .position(|option| *option == name)
+            .with_context(|| format!("No option named {}", name))
+    };
+
+    let votes: Vec<String> = match (ptype, format) {
+        (PollType::Ranked(_), BallotFormat::Blt | BallotFormat::Abif) => {
+            let ballots = match format {
+                BallotFormat::Blt => Ballot::parse_blt(data, options)?,
+                _ => Ballot::parse_abif(data, options)?,
+            };
+            // Weights are checked while parsing, but there can be many of them
+            let total = ballots
+                .iter()
+                .try_fold(0u64, |total, (_, weight)| total.checked_add(*weight))
+                .filter(|total| *total <= MAX_IMPORTED_BALLOTS);
+            if total.is_none() {
+                return Err(anyhow!(
+                    "Too many ballots, at most {} can be imported at once",
+                    MAX_IMPORTED_BALLOTS
+                ));
+            }
+            ballots
+                .into_iter()
+                .flat_map(|(ballot, weight)| {
+                    std::iter::repeat_n(ballot.to_query(), weight as usize)
+                })
+                .collect()
+        }
+        (PollType::Single, BallotFormat::Csv) => read_csv(data)?
+            .iter()
+            .map(|row| match &row[..] {
+                [name] => Ok(format!("response={}", option(name)?)),
+                _ => Err(anyhow!("Expected a single option in every row")),
+            })
+            .collect::<anyhow::Result<_>>()?,
+        (PollType::Multiple, BallotFormat::Csv) => read_csv(data)?
+            .iter()
+            .map(|row| {
+                let chosen = row
+                    .iter()
+                    .map(|name| Ok(format!("response={}", option(name)?)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Ok(chosen.join("&"))
+            })
+            .collect::<anyhow::Result<_>>()?,
+        (PollType::Score, BallotFormat::Csv) => {
+            let mut rows = read_csv(data)?.into_iter();
+            let header = rows.next().context("Missing the header row")?;
+            let columns = header
+                .iter()
+                .map(|name| option(name))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            rows.map(|row| {
+                if row.len() != columns.len() {
+                    return Err(anyhow!("Expected {} columns in every row", columns.len()));
+                }
+                // Vote requests list the options in order
+                let mut points: Vec<_> = columns.iter().zip(&row).collect();
+                points.sort_unstable_by_key(|(option, _)| **option);
+                let points: Vec<_> = points
+                    .into_iter()
+                    .map(|(option, points)| format!("{}={}", option, points))
+                    .collect();
+                Ok(points.join("&"))
+            })
+            .collect::<anyhow::Result<_>>()?
+        }
+        (ptype, format) => {
+            return Err(anyhow!(
+                "{:?} files can't be imported to {} polls",
+                format,
+                ptype
+            ))
+        }
+    };
+    if votes.len() as u64 > MAX_IMPORTED_BALLOTS {
+        return Err(anyhow!(
+            "Too many ballots, at most {} can be imported at once",
+            MAX_IMPORTED_BALLOTS
+        ));
+    }
+    Ok(votes)
+}
+
+/// Registers imported votes on the poll. Either all of them are registered or none are.
+/// Returns the ballots to store, if the poll's format stores them.
+pub fn register_votes(poll: &mut Poll, votes: &[String]) -> anyhow::Result<Vec<Ballot>> {
+    // Validate everything on a copy, so that an invalid ballot doesn't leave a partial tally
+    let mut format =
+        super::create_poll_format_from_bytes(poll.data.ptype, poll.format.save_state()?)?;
+    let mut ballots = Vec::new();
+    for (index, vote) in votes.iter().enumerate() {
+        format
+            .register_votes(vote)
+            .with_context(|| format!("Invalid ballot {}", index + 1))?;
+        ballots.extend(format.ballot(vote));
+    }
+    poll.format = format;
+    poll.data.voters += votes.len() as u64;
+    Ok(ballots)
+}
+
+/// Reads CSV (RFC 4180) rows, skipping empty lines. Fields are trimmed.
+fn read_csv(data: &str) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = data.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            ',' if !quoted => row.push(std::mem::take(&mut field).trim().to_string()),
+            '\r' if !quoted => (),
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field).trim().to_string());
+                if row.iter().any(|f| !f.is_empty()) {
+                    rows.push(std::mem::take(&mut row));
+                }
+                row.clear();
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(anyhow!("Unterminated quoted field"));
+    }
+    row.push(field.trim().to_string());
+    if row.iter().any(|f| !f.is_empty()) {
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[test]
+fn test_read_votes() {
+    let options = ["a", "b, c", "d"];
+    let csv = "a\r\n\"b, c\"\n\nd";
+    assert_eq!(
+        read_votes(PollType::Single, &options, BallotFormat::Csv, csv).unwrap(),
+        ["response=0", "response=1", "response=2"]
+    );
+    assert!(read_votes(PollType::Single, &options, BallotFormat::Csv, "e").is_err());
+    assert_eq!(
+        read_votes(
+            PollType::Multiple,
+            &options,
+            BallotFormat::Csv,
+            "a,d\n\"b, c\""
+        )
+        .unwrap(),
+        ["response=0&response=2", "response=1"]
+    );
+    assert_eq!(
+        read_votes(
+            PollType::Score,
+            &options,
+            BallotFormat::Csv,
+            "d,a,\"b, c\"\n5,1,0"
+        )
+        .unwrap(),
+        ["0=1&1=0&2=5"]
+    );
+    assert_eq!(
+        read_votes(
+            PollType::try_parse("RankedBorda").unwrap(),
+            &options,
+            BallotFormat::Abif,
+            "2:d>a"
+        )
+        .unwrap(),
+        ["0=1&1=2&2=0", "0=1&1=2&2=0"]
+    );
+    assert!(read_votes(PollType::Score, &options, BallotFormat::Blt, "").is_err());
+}
+
+#[test]
+fn test_read_votes_weights() {
+    let options = ["a", "b"];
+    let ranked = PollType::try_parse("RankedBorda").unwrap();
+    for weight in [u64::MAX, 1 << 63, MAX_IMPORTED_BALLOTS + 1] {
+        let blt = format!("2 1\n{} 1 2 0\n0\na\nb\nPoll", weight);
+        assert!(read_votes(ranked, &options, BallotFormat::Blt, &blt).is_err());
+        let abif = format!("{}:a>b", weight);
+        assert!(read_votes(ranked, &options, BallotFormat::Abif, &abif).is_err());
+    }
+    // Weights that are allowed on their own, but not together
+    let abif = format!("{0}:a>b\n{0}:b>a", MAX_IMPORTED_BALLOTS);
+    assert!(read_votes(ranked, &options, BallotFormat::Abif, &abif).is_err());
+    let blt = format!("2 1\n{} 1 2 0\n0\na\nb\nPoll", MAX_IMPORTED_BALLOTS);
+    assert_eq!(
+        read_votes(ranked, &options, BallotFormat::Blt, &blt)
+            .unwrap()
+            .len() as u64,
+        MAX_IMPORTED_BALLOTS
+    );
+}
@@ -3,12 +3,14 @@ use anyhow::Context;
 use askama::Template;
 use bincode::Decode;
 use rand::Rng;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+pub mod import;
 mod ranked;
 mod score;
 mod simple;
 
+pub use ranked::{Ballot, BallotExport};
 use ranked::{BordaPoll, DowdallPoll};
 use score::ScoredChoicePoll;
 use simple::{MultipleChoicePoll, SingleChoicePoll};
@@ -62,14 +64,16 @@ impl PollType {
             _ => Err(ParseError::InvalidPollType(s.into())),
         }
     }
-    pub fn creation_site(&self) -> Result<String, askama::Error> {
+    pub fn creation_site(&self, ctx: &PageContext) -> Result<String, askama::Error> {
         let poll_type = *self;
         match self {
             PollType::Single | PollType::Multiple => {
-                simple::templates::SimpleCreateTemplate { poll_type }.render()
+                simple::templates::SimpleCreateTemplate { poll_type, ctx }.render()
             }
-            PollType::Ranked(_) => ranked::templates::RankedCreateTemplate { poll_type }.render(),
-            PollType::Score => score::templates::ScoreCreateTemplate { poll_type }.render(),
+            PollType::Ranked(_) => {
+                ranked::templates::RankedCreateTemplate { poll_type, ctx }.render()
+            }
+            PollType::Score => score::templates::ScoreCreateTemplate { poll_type, ctx }.render(),
         }
     }
 }
@@ -160,6 +164,138 @@ pub struct PollData {
     pub date_created: chrono::DateTime<chrono::Utc>,
     pub admin_link: String,
     pub voters: u64,
+    pub settings: PollSettings,
+}
+
+/// Optional poll behaviour, chosen when creating the poll
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PollSettings {
+    /// Refuse repeat votes from the same browser, see session::VoterCookie.
+    /// Works alongside the IP rate limits.
+    pub dedupe: bool,
+}
+
+/// A poll's options and tallies, independent of how its format stores them.
+/// Used to move polls between instances, see export.rs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PortableState {
+    pub options: Vec<PortableOption>,
+    /// The range of points voters can assign (inclusive), only in score polls
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub points: Option<(u32, u32)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PortableOption {
+    pub name: String,
+    pub tally: Tally,
+}
+
+/// The votes or points an option received
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum Tally {
+    Count(u64),
+    /// Fractional points, for example in Dowdall polls
+    Points(f32),
+}
+
+impl PortableState {
+    pub fn new<T: Copy + Into<Tally>>(options: &[(String, T)]) -> Self {
+        PortableState {
+            options: options
+                .iter()
+                .map(|(name, tally)| PortableOption {
+                    name: name.clone(),
+                    tally: (*tally).into(),
+                })
+                .collect(),
+            points: None,
+        }
+    }
+
+    /// The options of formats that count whole votes
+    pub fn counts(&self) -> Result<Vec<(String, u64)>, anyhow::Error> {
+        self.check_options()?;
+        self.options
+            .iter()
+            .map(|option| match option.tally {
+                Tally::Count(n) => Ok((option.name.clone(), n)),
+                Tally::Points(_) => Err(anyhow::anyhow!(
+                    "Expected a whole number of votes for option {}",
+                    option.name
+                )),
+            })
+            .collect()
+    }
+
+    /// The options of formats that count fractional points
+    pub fn points(&self) -> Result<Vec<(String, f32)>, anyhow::Error> {
+        self.check_options()?;
+        Ok(self
+            .options
+            .iter()
+            .map(|option| match option.tally {
+                Tally::Count(n) => (option.name.clone(), n as f32),
+                Tally::Points(p) => (option.name.clone(), p),
+            })
+            .collect())
+    }
+
+    /// The same limits as when creating a poll
+    fn check_options(&self) -> Result<(), anyhow::Error> {
+        if self.options.len() < 2 {
+            anyhow::bail!("Too few options specified");
+        }
+        Ok(())
+    }
+}
+
+impl Tally {
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            Tally::Count(n) => n as f64,
+            Tally::Points(p) => p as f64,
+        }
+    }
+}
+
+impl std::fmt::Display for Tally {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tally::Count(n) => n.fmt(f),
+            Tally::Points(p) => p.fmt(f),
+        }
+    }
+}
+
+impl From<u64> for Tally {
+    fn from(n: u64) -> Self {
+        Tally::Count(n)
+    }
+}
+
+impl From<f32> for Tally {
+    fn from(p: f32) -> Self {
+        Tally::Points(p)
+    }
+}
+
+/// Request-specific data needed to render the poll creation and voting websites
+pub struct PageContext<'a> {
+    /// Has to be submitted with every form, see security::CsrfToken
+    pub csrf_token: &'a str,
+    /// The proof of work challenge that has to be solved before submitting, see pow::Challenges
+    pub pow_challenge: Option<&'a str>,
+    /// Render the voting widget instead of the whole page, see embed.rs
+    pub embed: bool,
+}
+
+impl PageContext<'_> {
+    /// The proof of work challenge or an empty string, for use in templates
+    pub fn pow_challenge(&self) -> &str {
+        self.pow_challenge.unwrap_or_default()
+    }
 }
 
 pub trait PollFormat: Send + Sync + 'static {
@@ -171,13 +307,19 @@ pub trait PollFormat: Send + Sync + 'static {
         Self: Sized;
 
     /// Return HTML of the website for voting
-    fn voting_site(&self, data: &PollData) -> Result<String, askama::Error>;
+    fn voting_site(&self, data: &PollData, ctx: &PageContext) -> Result<String, askama::Error>;
     /// Return HTML of the poll's results
     fn results_site(&self, data: &PollData) -> Result<String, askama::Error>;
     /// Register a new voting request: for example add points to the options
     /// the user voted for.
     fn register_votes(&mut self, query: &str) -> Result<(), anyhow::Error>;
 
+    /// Return the ballot of a vote registered with register_votes, for formats that
+    /// store ballots (ranked polls)
+    fn ballot(&self, _query: &str) -> Option<Ballot> {
+        None
+    }
+
     /// Save the poll's data into bytes for storing inside a database
     fn save_state(&self) -> Result<Vec<u8>, anyhow::Error>;
 
@@ -195,6 +337,14 @@ pub trait PollFormat: Send + Sync + 'static {
 
     /// Reset the poll's state to as if it was just created (used by admin options)
     fn reset(&mut self);
+
+    /// Return the poll's options and tallies in a portable form (used by exports)
+    fn export_state(&self) -> PortableState;
+
+    /// Restore a poll exported with export_state, possibly by another instance
+    fn from_portable(state: &PortableState) -> Result<Box<dyn PollFormat>, anyhow::Error>
+    where
+        Self: Sized;
 }
 
 pub fn create_poll_format_from_data(
@@ -233,6 +383,28 @@ pub fn create_poll_format_from_bytes(
     }
 }
 
+impl PollType {
+    /// Whether votes on polls of this type are stored as ballots, see PollFormat::ballot
+    pub fn has_ballots(&self) -> bool {
+        matches!(self, PollType::Ranked(_))
+    }
+}
+
+pub fn create_poll_format_from_portable(
+    ptype: PollType,
+    state: &PortableState,
+) -> Result<Box<dyn PollFormat>, anyhow::Error> {
+    match ptype {
+        PollType::Single => SingleChoicePoll::from_portable(state),
+        PollType::Multiple => MultipleChoicePoll::from_portable(state),
+        PollType::Score => ScoredChoicePoll::from_portable(state),
+        PollType::Ranked(sys) => match sys {
+            PositionalSystem::Borda => BordaPoll::from_portable(state),
+            PositionalSystem::Dowdall => DowdallPoll::from_portable(state),
+        },
+    }
+}
+
 #[test]
 fn test_poll_id() {
     let poll_id = PollID(12, 5732390254647088000);
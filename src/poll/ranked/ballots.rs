@@ -0,0 +1,437 @@
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::poll::import::MAX_IMPORTED_BALLOTS;
+use crate::util;
+
+/// A single voter's ranking in a ranked poll: the place (0 being the first)
+/// they assigned to each option, in the order of the poll's options.
+/// Stored separately from the poll's tallies, so that the results can be
+/// verified with external tools.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct Ballot(pub Vec<u32>);
+
+impl Ballot {
+    /// Reads a ballot from a ranked vote request, see BordaPoll::register_votes.
+    /// Places outside of the range of options are refused, they used to overflow Borda tallies.
+    pub fn parse(query: &str, num_opts: usize) -> anyhow::Result<Self> {
+        let opts = util::parse_poll_opts(query, num_opts)?;
+        let mut places = Vec::with_capacity(num_opts);
+        for (index, (opt_index, opt_place)) in opts.into_iter().enumerate() {
+            if opt_index as usize != index {
+                return Err(anyhow!(
+                    "unexpected index: {}, expected {}",
+                    opt_index,
+                    index
+                ));
+            }
+            places.push(opt_place);
+        }
+        let ballot = Ballot(places);
+        ballot.check(num_opts)?;
+        Ok(ballot)
+    }
+
+    /// Checks that the ballot can belong to a poll with the given number of options
+    pub fn check(&self, num_opts: usize) -> anyhow::Result<()> {
+        if self.0.len() != num_opts {
+            return Err(anyhow!(
+                "Expected a place for each of {} options, got {}",
+                num_opts,
+                self.0.len()
+            ));
+        }
+        if self.0.iter().any(|place| *place as usize >= num_opts) {
+            return Err(anyhow!("Place outside of the range of options"));
+        }
+        Ok(())
+    }
+
+    /// The indexes of the options grouped by place, from the first place.
+    /// Options sharing a place are tied.
+    pub fn ranking(&self) -> Vec<Vec<usize>> {
+        let mut places: Vec<u32> = self.0.clone();
+        places.sort_unstable();
+        places.dedup();
+        places
+            .into_iter()
+            .map(|place| {
+                (0..self.0.len())
+                    .filter(|option| self.0[*option] == place)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Creates a ballot from options grouped by place (see Ballot::ranking).
+    /// Options that weren't ranked share the last place.
+    pub fn from_ranking(ranking: &[Vec<usize>], num_opts: usize) -> anyhow::Result<Self> {
+        let mut places = vec![None; num_opts];
+        for (place, options) in ranking.iter().enumerate() {
+            for option in options {
+                let slot = places
+                    .get_mut(*option)
+                    .context("Option number out of range")?;
+                if slot.is_some() {
+                    return Err(anyhow!("Option {} ranked twice", option + 1));
+                }
+                *slot = Some(place as u32);
+            }
+        }
+        let last = ranking.len() as u32;
+        Ok(Ballot(
+            places.into_iter().map(|p| p.unwrap_or(last)).collect(),
+        ))
+    }
+
+    /// The vote request that casts this ballot, see Ballot::parse
+    pub fn to_query(&self) -> String {
+        let opts: Vec<String> = self
+            .0
+            .iter()
+            .enumerate()
+            .map(|(index, place)| format!("{}={}", index, place))
+            .collect();
+        opts.join("&")
+    }
+
+    /// Reads ballots in the BLT format (see BallotExport::blt), along with their weights.
+    /// The candidates are matched with the options by name.
+    pub fn parse_blt(data: &str, options: &[&str]) -> anyhow::Result<Vec<(Ballot, u64)>> {
+        let mut lines = data.lines().map(str::trim).filter(|line| !line.is_empty());
+        let header = lines.next().context("Empty file")?;
+        let candidates: usize = header
+            .split_whitespace()
+            .next()
+            .and_then(|n| n.parse().ok())
+            .context("Expected the number of candidates on the first line")?;
+        if candidates != options.len() {
+            return Err(anyhow!(
+                "Expected {} candidates, found {}",
+                options.len(),
+                candidates
+            ));
+        }
+
+        let mut rankings = Vec::new();
+        let mut line = lines.next().context("Missing ballots")?;
+        // Withdrawn candidates
+        if line.starts_with('-') {
+            line = lines.next().context("Missing ballots")?;
+        }
+        while line != "0" {
+            let mut fields = line.split_whitespace();
+            let mut weight = fields.next().context("Empty ballot")?;
+            // Optional ballot ids
+            if weight.starts_with('(') {
+                weight = fields.next().context("Empty ballot")?;
+            }
+            let weight: u64 = weight
+                .parse()
+                .with_context(|| format!("Invalid ballot weight: {}", weight))?;
+            check_weight(weight)?;
+            let mut ranking = Vec::new();
+            for field in fields.take_while(|field| *field != "0") {
+                let place = field
+                    .split('=')
+                    .map(|c| match c.parse::<usize>() {
+                        Ok(c) if (1..=candidates).contains(&c) => Ok(c - 1),
+                        _ => Err(anyhow!("Invalid candidate: {}", c)),
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                ranking.push(place);
+            }
+            rankings.push((ranking, weight));
+            line = lines
+                .next()
+                .context("Missing the end of ballots (a line with 0)")?;
+        }
+
+        // Candidate numbers are in the order of the names after the ballots
+        let mut numbers = Vec::with_capacity(candidates);
+        for _ in 0..candidates {
+            let name = lines.next().context("Missing candidate names")?;
+            let name = name.trim_matches('"');
+            let option = options
+                .iter()
+                .position(|option| *option == name)
+                .with_context(|| format!("No option named {}", name))?;
+            numbers.push(option);
+        }
+        rankings
+            .into_iter()
+            .map(|(ranking, weight)| {
+                let ranking: Vec<Vec<usize>> = ranking
+                    .into_iter()
+                    .map(|place: Vec<usize>| place.into_iter().map(|c| numbers[c]).collect())
+                    .collect();
+                Ok((Ballot::from_ranking(&ranking, options.len())?, weight))
+            })
+            .collect()
+    }
+
+    /// Reads ballots in the Aggregated Ballot Information Format, along with their counts.
+    /// Candidates can be declared with tokens (`=token:[Name]`) or written as their names.
+    pub fn parse_abif(data: &str, options: &[&str]) -> anyhow::Result<Vec<(Ballot, u64)>> {
+        let mut tokens: HashMap<&str, usize> = HashMap::new();
+        let option = |tokens: &HashMap<&str, usize>, token: &str| {
+            let token = token.trim();
+            let name = token
+                .strip_prefix('[')
+                .and_then(|t| t.strip_suffix(']'))
+                .unwrap_or(token);
+            tokens
+                .get(token)
+                .copied()
+                .or_else(|| options.iter().position(|option| *option == name))
+                .with_context(|| format!("Unknown candidate: {}", token))
+        };
+
+        let mut ballots = Vec::new();
+        for line in data.lines() {
+            // Comments and metadata
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() || line.starts_with('{') {
+                continue;
+            }
+            if let Some(declaration) = line.strip_prefix('=') {
+                let (token, name) = declaration
+                    .split_once(':')
+                    .with_context(|| format!("Invalid candidate declaration: {}", line))?;
+                tokens.insert(token.trim(), option(&tokens, name)?);
+                continue;
+            }
+            let (count, ranking) = line
+                .split_once(':')
+                .with_context(|| format!("Expected a ballot count: {}", line))?;
+            let count: u64 = count
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid ballot count: {}", count))?;
+            check_weight(count)?;
+            let ranking: Vec<Vec<usize>> = ranking
+                .split('>')
+                .map(|place| place.split('=').map(|t| option(&tokens, t)).collect())
+                .collect::<anyhow::Result<_>>()?;
+            ballots.push((Ballot::from_ranking(&ranking, options.len())?, count));
+        }
+        Ok(ballots)
+    }
+
+    /// The format used in the database: comma-separated places
+    pub fn to_db(&self) -> String {
+        let places: Vec<String> = self.0.iter().map(u32::to_string).collect();
+        places.join(",")
+    }
+
+    pub fn from_db(value: &str) -> anyhow::Result<Self> {
+        value
+            .split(',')
+            .map(|place| place.parse().context("Invalid place in ballot"))
+            .collect::<anyhow::Result<_>>()
+            .map(Ballot)
+    }
+}
+
+/// Refuses ballot weights that couldn't be imported anyway, before they're added up
+fn check_weight(weight: u64) -> anyhow::Result<()> {
+    if weight > MAX_IMPORTED_BALLOTS {
+        return Err(anyhow!(
+            "Ballot weight {} is too large, at most {} ballots can be imported at once",
+            weight,
+            MAX_IMPORTED_BALLOTS
+        ));
+    }
+    Ok(())
+}
+
+/// Ballots of a poll in the formats of external election tools.
+/// Identical rankings are counted together, so the order of the votes isn't revealed.
+pub struct BallotExport<'a> {
+    name: &'a str,
+    options: Vec<&'a str>,
+    /// Unique rankings (see Ballot::ranking) and the number of voters who chose each,
+    /// the most common first
+    rankings: Vec<(Vec<Vec<usize>>, u64)>,
+}
+
+impl<'a> BallotExport<'a> {
+    pub fn new(name: &'a str, options: Vec<&'a str>, ballots: &[Ballot]) -> Self {
+        let mut counts: HashMap<&Ballot, u64> = HashMap::new();
+        for ballot in ballots {
+            *counts.entry(ballot).or_default() += 1;
+        }
+        let mut rankings: Vec<_> = counts
+            .into_iter()
+            .map(|(ballot, count)| (ballot.ranking(), count))
+            .collect();
+        rankings.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        BallotExport {
+            name,
+            options,
+            rankings,
+        }
+    }
+
+    fn voters(&self) -> u64 {
+        self.rankings.iter().map(|(_, count)| count).sum()
+    }
+
+    fn has_ties(&self) -> bool {
+        self.rankings
+            .iter()
+            .any(|(ranking, _)| ranking.iter().any(|place| place.len() > 1))
+    }
+
+    /// Writes a ranking with 1-based option numbers, the places separated by `separator`
+    /// and the tied options by `tie`
+    fn write_ranking(ranking: &[Vec<usize>], separator: &str, tie: &str) -> String {
+        let places: Vec<String> = ranking
+            .iter()
+            .map(|place| {
+                let options: Vec<String> = place.iter().map(|o| (o + 1).to_string()).collect();
+                options.join(tie)
+            })
+            .collect();
+        places.join(separator)
+    }
+
+    /// The BLT format of STV counting tools (for a single seat). Tied options are joined
+    /// with '=', which is understood by most of them.
+    pub fn blt(&self) -> String {
+        let mut blt = format!("{} 1\n", self.options.len());
+        for (ranking, count) in &self.rankings {
+            let _ = writeln!(
+                blt,
+                "{} {} 0",
+                count,
+                Self::write_ranking(ranking, " ", "=")
+            );
+        }
+        blt.push_str("0\n");
+        for option in self.options.iter().chain([&self.name]) {
+            let _ = writeln!(blt, "\"{}\"", option.replace('"', "'"));
+        }
+        blt
+    }
+
+    /// The Aggregated Ballot Information Format. Options are declared as `c1`, `c2`, ...
+    pub fn abif(&self) -> String {
+        let mut abif = format!(
+            "{{\"version\": \"0.1\"}}\n{{\"title\": {}}}\n",
+            serde_json::Value::from(self.name)
+        );
+        for (index, option) in self.options.iter().enumerate() {
+            // Names are written in square brackets, which they can't contain
+            let option = option.replace('[', "(").replace(']', ")");
+            let _ = writeln!(abif, "=c{}:[{}]", index + 1, option.replace('\n', " "));
+        }
+        for (ranking, count) in &self.rankings {
+            let ranking = Self::write_ranking(ranking, ">c", "=c");
+            let _ = writeln!(abif, "{}:c{}", count, ranking);
+        }
+        abif
+    }
+
+    /// A PrefLib file and its data type: "soc" (strict orders, complete list), or "toc"
+    /// (orders with ties, complete list) if any ballot has tied options. Every ballot ranks
+    /// all options, so the incomplete types don't occur.
+    pub fn preflib(&self) -> (&'static str, String) {
+        let data_type = if self.has_ties() { "toc" } else { "soc" };
+        let mut preflib = format!(
+            "# TITLE: {}\n# DATA TYPE: {}\n# NUMBER ALTERNATIVES: {}\n# NUMBER VOTERS: {}\n\
+             # NUMBER UNIQUE ORDERS: {}\n",
+            self.name.replace('\n', " "),
+            data_type,
+            self.options.len(),
+            self.voters(),
+            self.rankings.len()
+        );
+        for (index, option) in self.options.iter().enumerate() {
+            let _ = writeln!(
+                preflib,
+                "# ALTERNATIVE NAME {}: {}",
+                index + 1,
+                option.replace('\n', " ")
+            );
+        }
+        for (ranking, count) in &self.rankings {
+            let places: Vec<String> = ranking
+                .iter()
+                .map(|place| match place[..] {
+                    [option] => (option + 1).to_string(),
+                    _ => format!(
+                        "{{{}}}",
+                        Self::write_ranking(std::slice::from_ref(place), "", ",")
+                    ),
+                })
+                .collect();
+            let _ = writeln!(preflib, "{}: {}", count, places.join(","));
+        }
+        (data_type, preflib)
+    }
+}
+
+#[test]
+fn test_ballot_formats() {
+    let ballots = [
+        Ballot::parse("0=0&1=1&2=2", 3).unwrap(),
+        Ballot::parse("0=1&1=0&2=2", 3).unwrap(),
+        Ballot::parse("0=0&1=1&2=2", 3).unwrap(),
+    ];
+    assert!(Ballot::parse("0=0&1=3&2=2", 3).is_err());
+    assert_eq!(Ballot::from_db(&ballots[1].to_db()).unwrap(), ballots[1]);
+
+    let export = BallotExport::new("Lunch", vec!["Pizza", "Soup", "Salad"], &ballots);
+    assert_eq!(
+        export.blt(),
+        "3 1\n2 1 2 3 0\n1 2 1 3 0\n0\n\"Pizza\"\n\"Soup\"\n\"Salad\"\n\"Lunch\"\n"
+    );
+    assert!(export
+        .abif()
+        .ends_with("=c3:[Salad]\n2:c1>c2>c3\n1:c2>c1>c3\n"));
+    let (data_type, preflib) = export.preflib();
+    assert_eq!(data_type, "soc");
+    assert!(preflib.contains("# NUMBER VOTERS: 3\n"));
+    assert!(preflib.ends_with("2: 1,2,3\n1: 2,1,3\n"));
+
+    let options = ["Pizza", "Soup", "Salad"];
+    let blt = BallotExport::new("Lunch", options.to_vec(), &ballots).blt();
+    let parsed = Ballot::parse_blt(&blt, &options).unwrap();
+    assert_eq!(parsed, [(ballots[0].clone(), 2), (ballots[1].clone(), 1)]);
+    let abif = BallotExport::new("Lunch", options.to_vec(), &ballots).abif();
+    assert_eq!(Ballot::parse_abif(&abif, &options).unwrap(), parsed);
+    // Unranked options share the last place
+    let parsed = Ballot::parse_abif("3:[Soup]>Pizza\n", &options).unwrap();
+    assert_eq!(parsed, [(Ballot(vec![1, 0, 2]), 3)]);
+    assert!(Ballot::parse_abif("1:Soup>Soup\n", &options).is_err());
+    assert!(Ballot::parse_blt("2 1\n1 1 2 0\n0\n\"Pizza\"\n\"Soup\"\n", &options).is_err());
+
+    let tied = [Ballot(vec![0, 0, 1])];
+    let export = BallotExport::new("Lunch", vec!["Pizza", "Soup", "Salad"], &tied);
+    assert_eq!(export.preflib().0, "toc");
+    assert!(export.preflib().1.ends_with("1: {1,2},3\n"));
+    assert!(export.blt().contains("1 1=2 3 0\n"));
+}
+
+#[test]
+fn test_vote_place_range() {
+    use super::{BordaPoll, DowdallPoll};
+    use crate::poll::PollFormat;
+
+    let mut borda = BordaPoll::from_data("A,B,C").unwrap();
+    let mut dowdall = DowdallPoll::from_data("A,B,C").unwrap();
+    for poll in [borda.as_mut() as &mut dyn PollFormat, dowdall.as_mut()] {
+        assert!(poll.register_votes("0=0&1=1&2=2").is_ok());
+        assert!(poll.register_votes("0=0&1=1&2=3").is_err());
+        assert!(poll.register_votes("0=4294967295&1=0&2=1").is_err());
+    }
+    // Refused votes don't change the tallies
+    assert_eq!(
+        borda.export_state().options[0].tally,
+        crate::poll::Tally::Count(2)
+    );
+}
@@ -3,8 +3,8 @@ use askama::Template;
 use bincode::{Decode, Encode};
 
 use super::templates::*;
-use crate::poll::{PollData, PollFormat};
-use crate::util;
+use super::Ballot;
+use crate::poll::{PageContext, PollData, PollFormat, PortableState};
 
 #[derive(Encode, Decode)]
 pub struct BordaPoll {
@@ -26,10 +26,11 @@ impl PollFormat for BordaPoll {
         Ok(Box::new(BordaPoll { options }))
     }
 
-    fn voting_site(&self, data: &PollData) -> Result<String, askama::Error> {
+    fn voting_site(&self, data: &PollData, ctx: &PageContext) -> Result<String, askama::Error> {
         let options: Vec<_> = self.options.iter().map(|(opt, _)| opt.as_str()).collect();
         RankedVoteTemplate {
             poll: data,
+            ctx,
             options: &options,
             can_unranked: false,
             unique_scores: true,
@@ -54,30 +55,21 @@ impl PollFormat for BordaPoll {
     /// Format:
     /// {0}={p}&{1}={p}&...,{n-1}={p}&{n}={p}
     /// 0,1...n - the option index. Must be in exact order.
-    /// p - rank assigned to the option
+    /// p - rank assigned to the option, lower than the number of options
     fn register_votes(&mut self, query: &str) -> Result<(), anyhow::Error> {
-        let opts = util::parse_poll_opts(query, self.options.len())?;
-        for (index, (opt_index, opt_place)) in opts.iter().enumerate() {
-            // TODO: Check if place values are unique (wait until .is_sorted is stabilized?)
-            // Check the argument order. Avoids malicious requests that do not vote
-            // on some options or vote twice on one
-            if *opt_index as usize != index {
-                return Err(anyhow!(
-                    "unexpected index: {}, expected {}",
-                    opt_index,
-                    index
-                ));
-            }
-            let n = self.options.len() as u64;
-            let option = self
-                .options
-                .get_mut(index)
-                .context("Option number out of range")?;
-            option.1 += n - (*opt_place as u64 + 1);
+        // TODO: Check if place values are unique (wait until .is_sorted is stabilized?)
+        let ballot = Ballot::parse(query, self.options.len())?;
+        let n = self.options.len() as u64;
+        for (option, place) in self.options.iter_mut().zip(ballot.0) {
+            option.1 += n - (place as u64 + 1);
         }
         Ok(())
     }
 
+    fn ballot(&self, query: &str) -> Option<Ballot> {
+        Ballot::parse(query, self.options.len()).ok()
+    }
+
     fn save_state(&self) -> Result<Vec<u8>, anyhow::Error> {
         bincode::encode_to_vec(self, bincode::config::standard()).context("Failed to encode state")
     }
@@ -85,4 +77,17 @@ impl PollFormat for BordaPoll {
     fn reset(&mut self) {
         self.options.iter_mut().for_each(|(_, c)| *c = 0);
     }
+
+    fn export_state(&self) -> PortableState {
+        PortableState::new(&self.options)
+    }
+
+    fn from_portable(state: &PortableState) -> Result<Box<dyn PollFormat>, anyhow::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Box::new(BordaPoll {
+            options: state.counts()?,
+        }))
+    }
 }
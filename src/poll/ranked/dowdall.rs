@@ -5,8 +5,8 @@ use askama::Template;
 use bincode::{Decode, Encode};
 
 use super::templates::*;
-use crate::poll::{PollData, PollFormat};
-use crate::util;
+use super::Ballot;
+use crate::poll::{PageContext, PollData, PollFormat, PortableState};
 
 #[derive(Template)]
 #[template(path = "ranked/dowdall_results.html")]
@@ -36,10 +36,11 @@ impl PollFormat for DowdallPoll {
         Ok(Box::new(DowdallPoll { options }))
     }
 
-    fn voting_site(&self, data: &PollData) -> Result<String, askama::Error> {
+    fn voting_site(&self, data: &PollData, ctx: &PageContext) -> Result<String, askama::Error> {
         let options: Vec<_> = self.options.iter().map(|(opt, _)| opt.as_str()).collect();
         RankedVoteTemplate {
             poll: data,
+            ctx,
             options: &options,
             can_unranked: false,
             unique_scores: true,
@@ -71,30 +72,21 @@ impl PollFormat for DowdallPoll {
 
     /// Format:
     /// {p0},{p1},...,{pN-1},{pN}
-    /// p{N} - place assigned to the option number N
+    /// p{N} - place assigned to the option number N, lower than the number of options
     /// N - the number of poll options
     fn register_votes(&mut self, query: &str) -> Result<(), anyhow::Error> {
-        let opts = util::parse_poll_opts(query, self.options.len())?;
-        for (index, (opt_index, opt_place)) in opts.iter().enumerate() {
-            // TODO: Check if place values are unique (wait until .is_sorted is stabilized?)
-            // Check the argument order. Avoids malicious requests that do not vote
-            // on some options or vote twice on one
-            if *opt_index as usize != index {
-                return Err(anyhow!(
-                    "unexpected index: {}, expected {}",
-                    opt_index,
-                    index
-                ));
-            }
-            let option = self
-                .options
-                .get_mut(index)
-                .context("Option number out of range")?;
-            option.1 += 1.0 / (opt_place + 1) as f32;
+        // TODO: Check if place values are unique (wait until .is_sorted is stabilized?)
+        let ballot = Ballot::parse(query, self.options.len())?;
+        for (option, place) in self.options.iter_mut().zip(ballot.0) {
+            option.1 += 1.0 / (place + 1) as f32;
         }
         Ok(())
     }
 
+    fn ballot(&self, query: &str) -> Option<Ballot> {
+        Ballot::parse(query, self.options.len()).ok()
+    }
+
     fn save_state(&self) -> Result<Vec<u8>, anyhow::Error> {
         bincode::encode_to_vec(self, bincode::config::standard()).context("Failed to encode state")
     }
@@ -102,4 +94,17 @@ impl PollFormat for DowdallPoll {
     fn reset(&mut self) {
         self.options.iter_mut().for_each(|(_, c)| *c = 0.0);
     }
+
+    fn export_state(&self) -> PortableState {
+        PortableState::new(&self.options)
+    }
+
+    fn from_portable(state: &PortableState) -> Result<Box<dyn PollFormat>, anyhow::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Box::new(DowdallPoll {
+            options: state.points()?,
+        }))
+    }
 }
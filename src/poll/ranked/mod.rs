@@ -1,24 +1,28 @@
+mod ballots;
 mod borda;
 mod dowdall;
 
+pub use ballots::{Ballot, BallotExport};
 pub use borda::BordaPoll;
 pub use dowdall::DowdallPoll;
 
 pub mod templates {
-    use crate::poll::{PollData, PollType};
+    use crate::poll::{PageContext, PollData, PollType};
     use askama::Template;
 
     #[derive(Template)]
     #[template(path = "ranked/create.html")]
-    pub struct RankedCreateTemplate {
+    pub struct RankedCreateTemplate<'a> {
         /// Gets passed on to the handle_create_desc in a POST request
         pub poll_type: PollType,
+        pub ctx: &'a PageContext<'a>,
     }
 
     #[derive(Template)]
     #[template(path = "ranked/vote.html")]
     pub struct RankedVoteTemplate<'a> {
         pub poll: &'a PollData,
+        pub ctx: &'a PageContext<'a>,
         pub can_unranked: bool,
         pub unique_scores: bool,
         pub options: &'a [&'a str],
@@ -2,7 +2,7 @@ use anyhow::{anyhow, Context};
 use askama::Template;
 use bincode::{Decode, Encode};
 
-use crate::poll::{PollData, PollFormat, PollType};
+use crate::poll::{PageContext, PollData, PollFormat, PollType, PortableState};
 
 use crate::util;
 use templates::*;
@@ -12,15 +12,17 @@ pub mod templates {
 
     #[derive(Template)]
     #[template(path = "score/create.html")]
-    pub struct ScoreCreateTemplate {
+    pub struct ScoreCreateTemplate<'a> {
         /// Gets passed on to the handle_create_desc in a POST request
         pub poll_type: PollType,
+        pub ctx: &'a PageContext<'a>,
     }
 
     #[derive(Template)]
     #[template(path = "score/vote.html")]
     pub struct ScoreVoteTemplate<'a> {
         pub poll: &'a PollData,
+        pub ctx: &'a PageContext<'a>,
         pub points_min: u32,
         pub points_max: u32,
         pub options: &'a [(&'a str, u64)],
@@ -76,7 +78,7 @@ impl PollFormat for ScoredChoicePoll {
         }))
     }
 
-    fn voting_site(&self, data: &PollData) -> Result<String, askama::Error> {
+    fn voting_site(&self, data: &PollData, ctx: &PageContext) -> Result<String, askama::Error> {
         let options: Vec<_> = self
             .options
             .iter()
@@ -85,6 +87,7 @@ impl PollFormat for ScoredChoicePoll {
 
         ScoreVoteTemplate {
             poll: data,
+            ctx,
             points_min: self.points_min,
             points_max: self.points_max,
             options: &options,
@@ -144,4 +147,26 @@ impl PollFormat for ScoredChoicePoll {
     fn reset(&mut self) {
         self.options.iter_mut().for_each(|(_, c)| *c = 0);
     }
+
+    fn export_state(&self) -> PortableState {
+        PortableState {
+            points: Some((self.points_min, self.points_max)),
+            ..PortableState::new(&self.options)
+        }
+    }
+
+    fn from_portable(state: &PortableState) -> Result<Box<dyn PollFormat>, anyhow::Error>
+    where
+        Self: Sized,
+    {
+        let (points_min, points_max) = state.points.context("Missing the range of points")?;
+        if points_min >= points_max {
+            return Err(anyhow!("points_min must be lower than points_max"));
+        }
+        Ok(Box::new(ScoredChoicePoll {
+            points_min,
+            points_max,
+            options: state.counts()?,
+        }))
+    }
 }
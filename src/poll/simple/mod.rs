@@ -4,21 +4,23 @@ pub use multiple::MultipleChoicePoll;
 pub use single::SingleChoicePoll;
 
 pub mod templates {
-    use crate::poll::{PollData, PollType};
+    use crate::poll::{PageContext, PollData, PollType};
     use askama::Template;
 
     #[derive(Template)]
     #[template(path = "simple/create.html")]
-    pub struct SimpleCreateTemplate {
+    pub struct SimpleCreateTemplate<'a> {
         /// Determines whether to display radio buttons (single choice)
         /// or checkboxes (multiple choice)
         pub poll_type: PollType,
+        pub ctx: &'a PageContext<'a>,
     }
 
     #[derive(Template)]
     #[template(path = "simple/vote.html")]
     pub struct SimpleVoteTemplate<'a> {
         pub poll: &'a PollData,
+        pub ctx: &'a PageContext<'a>,
         pub multiple: bool,
         pub options: &'a [(&'a str, u64)],
     }
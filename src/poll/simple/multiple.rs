@@ -3,7 +3,7 @@ use askama::Template;
 use bincode::{Decode, Encode};
 
 use super::templates::*;
-use crate::poll::{PollData, PollFormat};
+use crate::poll::{PageContext, PollData, PollFormat, PortableState};
 
 #[derive(Encode, Decode)]
 pub struct MultipleChoicePoll {
@@ -25,7 +25,7 @@ impl PollFormat for MultipleChoicePoll {
         Ok(Box::new(MultipleChoicePoll { options }))
     }
 
-    fn voting_site(&self, data: &PollData) -> Result<String, askama::Error> {
+    fn voting_site(&self, data: &PollData, ctx: &PageContext) -> Result<String, askama::Error> {
         let options: Vec<_> = self
             .options
             .iter()
@@ -33,6 +33,7 @@ impl PollFormat for MultipleChoicePoll {
             .collect();
         SimpleVoteTemplate {
             poll: data,
+            ctx,
             options: &options,
             multiple: true,
         }
@@ -55,22 +56,24 @@ impl PollFormat for MultipleChoicePoll {
 
     /// Format:
     /// response={n}&...&response={n}
-    /// n - indexes of options selected
+    /// n - indexes of options selected, each at most once
     fn register_votes(&mut self, query: &str) -> Result<(), anyhow::Error> {
-        if query.chars().filter(|c| *c == '&').count() + 1 != self.options.len() {
-            return Err(anyhow!(
-                "The number of query elements must be equal to the number of poll options"
-            ));
-        }
+        let mut selected = Vec::new();
         for opt in query.split('&') {
             if !opt.starts_with("response=") {
                 return Err(anyhow!("Expected 'response' query element"));
             }
             let opt: usize = opt[9..].parse().context("'response' must be a number")?;
-            self.options
-                .get_mut(opt)
-                .context("'response' is outside of the range of options")?
-                .1 += 1;
+            if opt >= self.options.len() {
+                return Err(anyhow!("'response' is outside of the range of options"));
+            }
+            if selected.contains(&opt) {
+                return Err(anyhow!("The same option can't be selected twice"));
+            }
+            selected.push(opt);
+        }
+        for opt in selected {
+            self.options[opt].1 += 1;
         }
         Ok(())
     }
@@ -91,4 +94,33 @@ impl PollFormat for MultipleChoicePoll {
     fn reset(&mut self) {
         self.options.iter_mut().for_each(|(_, c)| *c = 0);
     }
+
+    fn export_state(&self) -> PortableState {
+        PortableState::new(&self.options)
+    }
+
+    fn from_portable(state: &PortableState) -> Result<Box<dyn PollFormat>, anyhow::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Box::new(MultipleChoicePoll {
+            options: state.counts()?,
+        }))
+    }
+}
+
+#[test]
+fn test_register_votes() {
+    let mut poll = MultipleChoicePoll::from_data("A,B,C").unwrap();
+    // Any number of the options can be selected, not only all of them
+    poll.register_votes("response=0&response=2").unwrap();
+    poll.register_votes("response=1").unwrap();
+    poll.register_votes("response=0&response=1&response=2")
+        .unwrap();
+    assert!(poll.register_votes("response=0&response=0").is_err());
+    assert!(poll.register_votes("response=3").is_err());
+    // Refused votes don't change the tallies, even partially
+    assert!(poll.register_votes("response=1&response=5").is_err());
+    let tallies: Vec<u64> = poll.options.iter().map(|(_, n)| *n).collect();
+    assert_eq!(tallies, [2, 2, 2]);
 }
@@ -26,7 +26,7 @@ impl PollFormat for SingleChoicePoll {
         Ok(Box::new(SingleChoicePoll { options }))
     }
 
-    fn voting_site(&self, data: &PollData) -> Result<String, askama::Error> {
+    fn voting_site(&self, data: &PollData, ctx: &PageContext) -> Result<String, askama::Error> {
         let options: Vec<_> = self
             .options
             .iter()
@@ -34,6 +34,7 @@ impl PollFormat for SingleChoicePoll {
             .collect();
         SimpleVoteTemplate {
             poll: data,
+            ctx,
             options: &options,
             multiple: false,
         }
@@ -85,4 +86,17 @@ impl PollFormat for SingleChoicePoll {
     fn reset(&mut self) {
         self.options.iter_mut().for_each(|(_, c)| *c = 0);
     }
+
+    fn export_state(&self) -> PortableState {
+        PortableState::new(&self.options)
+    }
+
+    fn from_portable(state: &PortableState) -> Result<Box<dyn PollFormat>, anyhow::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Box::new(SingleChoicePoll {
+            options: state.counts()?,
+        }))
+    }
 }
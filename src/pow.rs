@@ -0,0 +1,318 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::UserError;
+use crate::rate::Route;
+use crate::session::SessionKey;
+use crate::util;
+
+/// The prefix of signed challenge messages, so that the signatures can't be mistaken for others
+const POW_MESSAGE: &str = "pollinator_pow";
+/// How long a challenge can be solved and submitted after it was issued.
+/// Long enough to fill out a form without hurrying.
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(30 * 60);
+/// The window over which the volume of requests is measured
+const VOLUME_WINDOW: Duration = Duration::from_secs(60);
+
+/// Proof of work settings, see Challenges
+#[derive(Debug, Clone, Copy)]
+pub struct PowConfig {
+    /// The number of leading zero bits required from solutions. 0 turns proof of work off.
+    pub difficulty: u8,
+    /// The difficulty can't be raised above this
+    pub max_difficulty: u8,
+    /// The number of solved challenges per minute (for a single route) after which the
+    /// difficulty starts to rise: it's increased by one bit every time the volume doubles.
+    /// 0 keeps the difficulty constant.
+    pub threshold: u32,
+}
+
+/// A correctly solved challenge that hasn't been used yet, see Challenges::redeem
+pub struct Solved {
+    route: Route,
+    nonce: String,
+    expires: i64,
+}
+
+/// Counts solved challenges in a sliding window
+struct Volume {
+    window_start: Instant,
+    current: u32,
+    previous: u32,
+}
+
+impl Volume {
+    fn new(now: Instant) -> Self {
+        Volume {
+            window_start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= VOLUME_WINDOW * 2 {
+            *self = Volume::new(now);
+        } else if elapsed >= VOLUME_WINDOW {
+            self.previous = self.current;
+            self.current = 0;
+            self.window_start += VOLUME_WINDOW;
+        }
+    }
+
+    /// Estimates the number of requests in the last window
+    fn rate(&mut self, now: Instant) -> u32 {
+        self.advance(now);
+        let elapsed = now.saturating_duration_since(self.window_start);
+        let previous_weight = 1.0 - elapsed.as_secs_f64() / VOLUME_WINDOW.as_secs_f64();
+        self.current + (self.previous as f64 * previous_weight) as u32
+    }
+}
+
+/// A hashcash-style proof of work, required on the routes that bots could abuse
+/// (creating polls and voting) without having to use accounts or third party CAPTCHAs.
+///
+/// Challenges are issued with the creation and voting pages in format:
+/// `{route}.{difficulty}.{expires}.{nonce}.{signature}`
+/// A solution is a number `n` such that the SHA-256 hash of `{challenge}.{n}` starts with
+/// `difficulty` zero bits. The challenges are signed, so the server only has to remember the
+/// ones already used (until they expire).
+pub struct Challenges {
+    config: PowConfig,
+    /// Nonces of used challenges and the unix timestamps at which they expire
+    used: Mutex<HashMap<String, i64>>,
+    volume: Mutex<HashMap<Route, Volume>>,
+}
+
+impl Challenges {
+    pub fn new(config: PowConfig) -> Self {
+        Challenges {
+            config,
+            used: Mutex::new(HashMap::new()),
+            volume: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.difficulty > 0
+    }
+
+    /// The difficulty of new challenges, scaled with the volume of requests on the route
+    fn difficulty(&self, route: Route) -> u8 {
+        let PowConfig {
+            difficulty,
+            max_difficulty,
+            threshold,
+        } = self.config;
+        let now = Instant::now();
+        let rate = self
+            .volume
+            .lock()
+            .unwrap()
+            .entry(route)
+            .or_insert_with(|| Volume::new(now))
+            .rate(now);
+        let extra = match threshold {
+            0 => 0,
+            _ if rate < threshold => 0,
+            _ => (rate / threshold).ilog2() + 1,
+        };
+        (difficulty as u32 + extra).min(max_difficulty.max(difficulty) as u32) as u8
+    }
+
+    /// Issues a new challenge for the route. Returns None if proof of work is turned off.
+    pub fn issue(&self, key: &SessionKey, route: Route) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let expires = chrono::Utc::now().timestamp() + CHALLENGE_LIFETIME.as_secs() as i64;
+        let nonce = util::random_base64_u64();
+        let payload = format!("{}.{}.{}.{}", route, self.difficulty(route), expires, nonce);
+        let signature = key.sign(&format!("{}.{}", POW_MESSAGE, payload));
+        Some(format!("{}.{}", payload, signature))
+    }
+
+    /// Checks a solution submitted for the route, without using up the challenge: that's done
+    /// by `redeem` once the rest of the request is valid, so that a rate limited or invalid
+    /// request doesn't make the user solve a new challenge.
+    /// Always succeeds (with None) if proof of work is turned off.
+    pub fn verify(
+        &self,
+        key: &SessionKey,
+        route: Route,
+        challenge: Option<&str>,
+        solution: Option<&str>,
+    ) -> Result<Option<Solved>, UserError> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        let (challenge, solution) = match (challenge, solution) {
+            (Some(challenge), Some(solution)) => (challenge, solution),
+            _ => return Err(UserError::InvalidProofOfWork),
+        };
+
+        let (payload, signature) = challenge
+            .rsplit_once('.')
+            .ok_or(UserError::InvalidProofOfWork)?;
+        if !key.verify(&format!("{}.{}", POW_MESSAGE, payload), signature) {
+            return Err(UserError::InvalidProofOfWork);
+        }
+        let (challenge_route, difficulty, expires, nonce) =
+            match payload.split('.').collect::<Vec<_>>()[..] {
+                [route, difficulty, expires, nonce] => (route, difficulty, expires, nonce),
+                _ => return Err(UserError::InvalidProofOfWork),
+            };
+        let difficulty: u32 = difficulty
+            .parse()
+            .map_err(|_| UserError::InvalidProofOfWork)?;
+        let expires: i64 = expires.parse().map_err(|_| UserError::InvalidProofOfWork)?;
+        if challenge_route != route.to_string() || expires < chrono::Utc::now().timestamp() {
+            return Err(UserError::InvalidProofOfWork);
+        }
+
+        // Solutions are decimal numbers; anything else could collide with the challenge format
+        if solution.is_empty() || !solution.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(UserError::InvalidProofOfWork);
+        }
+        let hash = Sha256::digest(format!("{}.{}", challenge, solution));
+        if leading_zeros(&hash) < difficulty {
+            return Err(UserError::InvalidProofOfWork);
+        }
+
+        if self.used.lock().unwrap().contains_key(nonce) {
+            return Err(UserError::InvalidProofOfWork);
+        }
+        Ok(Some(Solved {
+            route,
+            nonce: nonce.to_string(),
+            expires,
+        }))
+    }
+
+    /// Uses up a challenge checked by `verify`, so that it can't be submitted again.
+    /// Fails if it was used by another request in the meantime.
+    pub fn redeem(&self, solved: Option<Solved>) -> Result<(), UserError> {
+        let Some(solved) = solved else {
+            return Ok(());
+        };
+        if self
+            .used
+            .lock()
+            .unwrap()
+            .insert(solved.nonce, solved.expires)
+            .is_some()
+        {
+            return Err(UserError::InvalidProofOfWork);
+        }
+
+        let now = Instant::now();
+        self.volume
+            .lock()
+            .unwrap()
+            .entry(solved.route)
+            .or_insert_with(|| Volume::new(now))
+            .current += 1;
+        Ok(())
+    }
+
+    /// Forgets used challenges that have expired (and can't be submitted again anyway)
+    pub fn cleanup(&self) {
+        let now = chrono::Utc::now().timestamp();
+        self.used
+            .lock()
+            .unwrap()
+            .retain(|_, expires| *expires >= now);
+    }
+}
+
+fn leading_zeros(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+fn solve(challenge: &str) -> String {
+    let difficulty: u32 = challenge.split('.').nth(1).unwrap().parse().unwrap();
+    (0u64..)
+        .map(|n| n.to_string())
+        .find(|n| leading_zeros(&Sha256::digest(format!("{}.{}", challenge, n))) >= difficulty)
+        .unwrap()
+}
+
+#[test]
+fn test_proof_of_work() {
+    let key = SessionKey::new(None);
+    let challenges = Challenges::new(PowConfig {
+        difficulty: 8,
+        max_difficulty: 8,
+        threshold: 0,
+    });
+
+    let challenge = challenges.issue(&key, Route::Vote).unwrap();
+    let solution = solve(&challenge);
+    // Wrong route
+    assert!(challenges
+        .verify(&key, Route::Create, Some(&challenge), Some(&solution))
+        .is_err());
+    // Checking a solution doesn't use up the challenge
+    assert!(challenges
+        .verify(&key, Route::Vote, Some(&challenge), Some(&solution))
+        .is_ok());
+    let solved = challenges
+        .verify(&key, Route::Vote, Some(&challenge), Some(&solution))
+        .unwrap();
+    let again = challenges
+        .verify(&key, Route::Vote, Some(&challenge), Some(&solution))
+        .unwrap();
+    assert!(challenges.redeem(solved).is_ok());
+    // Challenges can't be reused, also by requests checked before it was used
+    assert!(challenges.redeem(again).is_err());
+    assert!(challenges
+        .verify(&key, Route::Vote, Some(&challenge), Some(&solution))
+        .is_err());
+
+    // The difficulty can't be lowered by the client
+    let challenge = challenges.issue(&key, Route::Vote).unwrap();
+    let forged = challenge.replacen(".8.", ".0.", 1);
+    assert!(challenges
+        .verify(&key, Route::Vote, Some(&forged), Some("0"))
+        .is_err());
+}
+
+#[test]
+fn test_difficulty_scaling() {
+    let challenges = Challenges::new(PowConfig {
+        difficulty: 4,
+        max_difficulty: 6,
+        threshold: 10,
+    });
+    let now = Instant::now();
+    let set_rate = |rate| {
+        let mut volume = Volume::new(now);
+        volume.current = rate;
+        challenges
+            .volume
+            .lock()
+            .unwrap()
+            .insert(Route::Create, volume);
+    };
+
+    set_rate(9);
+    assert_eq!(challenges.difficulty(Route::Create), 4);
+    set_rate(10);
+    assert_eq!(challenges.difficulty(Route::Create), 5);
+    set_rate(20);
+    assert_eq!(challenges.difficulty(Route::Create), 6);
+    set_rate(1000);
+    assert_eq!(challenges.difficulty(Route::Create), 6);
+    assert_eq!(challenges.difficulty(Route::Vote), 4);
+}
@@ -0,0 +1,69 @@
+//! QR codes of voting links, for sharing polls at in-person meetings
+
+use anyhow::Context;
+use qrcode::{render::svg, Color, EcLevel, QrCode};
+
+/// The size of a module (a single "pixel" of the code) in PNG images
+const PNG_SCALE: usize = 8;
+/// The empty border around the code required by scanners, in modules
+const QUIET_ZONE: usize = 4;
+
+/// Medium error correction survives smudged screens and prints, while keeping codes small
+fn encode(link: &str) -> anyhow::Result<QrCode> {
+    QrCode::with_error_correction_level(link, EcLevel::M).context("Failed to encode the QR code")
+}
+
+/// Renders the QR code of a link as a black on white SVG image
+pub fn svg(link: &str) -> anyhow::Result<String> {
+    Ok(encode(link)?
+        .render::<svg::Color>()
+        .min_dimensions(256, 256)
+        .quiet_zone(true)
+        .build())
+}
+
+/// Renders the QR code of a link as a black on white grayscale PNG image
+pub fn png(link: &str) -> anyhow::Result<Vec<u8>> {
+    let code = encode(link)?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    let size = (modules + 2 * QUIET_ZONE) * PNG_SCALE;
+
+    let mut pixels = vec![u8::MAX; size * size];
+    for (index, color) in colors.iter().enumerate() {
+        if *color == Color::Light {
+            continue;
+        }
+        let x = (index % modules + QUIET_ZONE) * PNG_SCALE;
+        let y = (index / modules + QUIET_ZONE) * PNG_SCALE;
+        for row in y..y + PNG_SCALE {
+            pixels[row * size + x..row * size + x + PNG_SCALE].fill(0);
+        }
+    }
+
+    let mut image = Vec::new();
+    let mut encoder = png::Encoder::new(&mut image, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(image)
+}
+
+#[test]
+fn test_qr_codes() {
+    let link = "https://polls.example.com/vote/5+abcdefghijk";
+    let svg = svg(link).unwrap();
+    assert!(svg.contains("<svg") && svg.ends_with("</svg>"));
+
+    let png = png(link).unwrap();
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    let decoder = png::Decoder::new(png.as_slice());
+    let reader = decoder.read_info().unwrap();
+    let size = reader.info().width as usize;
+    assert_eq!(size, reader.info().height as usize);
+    assert_eq!(size % PNG_SCALE, 0);
+    assert_eq!(
+        size / PNG_SCALE,
+        encode(link).unwrap().width() + 2 * QUIET_ZONE
+    );
+}
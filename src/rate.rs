@@ -1,137 +1,684 @@
-use std::net::IpAddr;
-use std::ops::{Deref, DerefMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::{Duration, Instant};
 
+use actix_web::http::header::{HeaderMap, HeaderName, FORWARDED, X_FORWARDED_FOR};
 use actix_web::{web, HttpRequest};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use std::collections::HashMap;
 use std::sync::Mutex;
 
+use crate::error::UserError;
 use crate::poll::PollID;
+use crate::util;
 
-#[derive(Default)]
-pub struct LimitMap(HashMap<IpAddr, Instant>);
+/// Rate limiting policy, enforced with the generic cell rate algorithm (GCRA)
+#[derive(Debug, Clone, Copy)]
+pub struct Policy {
+    /// On average, one action is allowed per this amount of time
+    pub period: Duration,
+    /// The number of actions that can be executed at once before getting limited
+    pub burst: u32,
+}
 
-impl Deref for LimitMap {
-    type Target = HashMap<IpAddr, Instant>;
+impl Policy {
+    pub const fn new(period: Duration, burst: u32) -> Self {
+        Policy { period, burst }
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// Reads a policy from "{prefix}_LIMIT" (period in seconds) and "{prefix}_BURST"
+    /// environmental variables. A period of 0 seconds disables the policy.
+    pub fn from_env_or(prefix: &str, default: Option<Policy>) -> anyhow::Result<Option<Self>> {
+        let default = default.unwrap_or(Policy::new(Duration::ZERO, 1));
+        let period = util::get_env_duration_or(&format!("{}_LIMIT", prefix), default.period)?;
+        let burst = util::get_env_number_or(&format!("{}_BURST", prefix), default.burst)?;
+        if period.is_zero() || burst == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(Policy::new(period, burst)))
+        }
+    }
+
+    /// How far ahead of the current time the theoretical arrival time can get
+    fn tolerance(&self) -> Duration {
+        self.period * (self.burst - 1)
     }
 }
-impl DerefMut for LimitMap {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+
+impl std::fmt::Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "1 per {} seconds, bursts of {}",
+            self.period.as_secs(),
+            self.burst
+        )
+    }
+}
+
+/// Routes that are rate limited
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Route {
+    Create,
+    /// Limited separately for every poll
+    Vote,
+    Results,
+    AdminLogin,
+}
+
+impl std::fmt::Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self))
+    }
+}
+
+impl Route {
+    pub fn try_parse(s: &str) -> Option<Self> {
+        match s {
+            "Create" => Some(Route::Create),
+            "Vote" => Some(Route::Vote),
+            "Results" => Some(Route::Results),
+            "AdminLogin" => Some(Route::AdminLogin),
+            _ => None,
+        }
     }
 }
 
+/// Limits applied to a single route
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RoutePolicy {
+    /// Limits every client (network, see LimitStore::aggregate) separately
+    pub client: Option<Policy>,
+    /// Limits all clients together
+    pub global: Option<Policy>,
+}
+
+impl RoutePolicy {
+    /// Reads the policies from "POLL_{name}_LIMIT", "POLL_{name}_BURST",
+    /// "POLL_{name}_GLOBAL_LIMIT" and "POLL_{name}_GLOBAL_BURST" environmental variables.
+    pub fn from_env_or(name: &str, default: RoutePolicy) -> anyhow::Result<Self> {
+        Ok(RoutePolicy {
+            client: Policy::from_env_or(&format!("POLL_{}", name), default.client)?,
+            global: Policy::from_env_or(&format!("POLL_{}_GLOBAL", name), default.global)?,
+        })
+    }
+}
+
+impl std::fmt::Display for RoutePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.client {
+            Some(policy) => write!(f, "{} per client", policy)?,
+            None => write!(f, "unlimited per client")?,
+        }
+        match self.global {
+            Some(policy) => write!(f, ", {} globally", policy),
+            None => write!(f, ", unlimited globally"),
+        }
+    }
+}
+
+/// A single limit in a form that can be stored outside of the LimitStore (see db::save_limits)
+pub struct LimitRecord {
+    pub route: Route,
+    /// None for global limits
+    pub addr: Option<IpAddr>,
+    pub poll: Option<usize>,
+    /// The time at which the limit is fully recovered
+    pub expires: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct LimitKey {
+    route: Route,
+    /// None for global limits
+    addr: Option<IpAddr>,
+    poll: Option<usize>,
+}
+
 #[derive(Default)]
 pub struct LimitStore {
-    pub create: Mutex<HashMap<IpAddr, Instant>>,
-    pub vote: Mutex<HashMap<(IpAddr, usize), Instant>>,
-    create_limit: Duration,
-    vote_limit: Duration,
+    /// Theoretical arrival time (the time at which the limit is fully recovered) of every key
+    limits: Mutex<HashMap<LimitKey, Instant>>,
+    policies: HashMap<Route, RoutePolicy>,
+    /// All IPv4 addresses sharing this many leading bits share their limits (32 by default)
+    ipv4_prefix: u8,
+    /// All IPv6 addresses sharing this many leading bits share their limits (64 by default,
+    /// since a single client usually has a whole /64 network to itself)
+    ipv6_prefix: u8,
 }
 
 impl LimitStore {
-    pub fn new(create_limit: Duration, vote_limit: Duration) -> Self {
-        LimitStore {
-            create_limit,
-            vote_limit,
+    pub fn new(
+        policies: HashMap<Route, RoutePolicy>,
+        ipv4_prefix: u8,
+        ipv6_prefix: u8,
+    ) -> anyhow::Result<Self> {
+        if ipv4_prefix > 32 || ipv6_prefix > 128 {
+            anyhow::bail!(
+                "Invalid address prefix length: /{} or /{}",
+                ipv4_prefix,
+                ipv6_prefix
+            );
+        }
+        Ok(LimitStore {
+            policies,
+            ipv4_prefix,
+            ipv6_prefix,
             ..Default::default()
+        })
+    }
+
+    /// Returns the address of the network the address belongs to, which the limits are kept for
+    pub fn aggregate(&self, addr: IpAddr) -> IpAddr {
+        let aggregate_v4 = |addr: Ipv4Addr| {
+            // The prefix length is checked in LimitStore::new
+            IpAddr::V4(Ipv4Net::new(addr, self.ipv4_prefix).unwrap().network())
+        };
+        match addr {
+            IpAddr::V4(addr) => aggregate_v4(addr),
+            IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+                // Clients connecting over IPv4 to a dual-stack socket
+                Some(addr) => aggregate_v4(addr),
+                None => IpAddr::V6(Ipv6Net::new(addr, self.ipv6_prefix).unwrap().network()),
+            },
         }
     }
 
     // Called periodically to clean up now-irrelevant limits
     pub fn cleanup(&self) {
         let now = Instant::now();
-        self.create
-            .lock()
-            .unwrap()
-            .retain(|_, v| now - *v <= self.create_limit);
-        self.vote
-            .lock()
-            .unwrap()
-            .retain(|_, v| now - *v <= self.vote_limit);
+        self.limits.lock().unwrap().retain(|_, tat| *tat > now);
     }
 
     /// Resets all limits
     pub fn reset(&self) {
-        self.create.lock().unwrap().clear();
-        self.vote.lock().unwrap().clear();
+        self.limits.lock().unwrap().clear();
     }
 
-    // Returns true if the address should be rate-limited;
-    // inserts it otherwise
-    pub fn check_create(&self, addr: IpAddr) -> bool {
-        let mut limits = self.create.lock().unwrap();
+    /// Returns all limits that haven't been fully recovered yet
+    pub fn export(&self) -> Vec<LimitRecord> {
         let now = Instant::now();
-        if let Some(instant) = limits.get(&addr) {
-            if now - *instant < self.create_limit {
-                true
-            } else {
-                limits.remove(&addr);
-                false
-            }
-        } else {
-            limits.insert(addr, now);
-            false
-        }
+        let wall_now = chrono::Utc::now();
+        self.limits
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, tat)| **tat > now)
+            .filter_map(|(key, tat)| {
+                Some(LimitRecord {
+                    route: key.route,
+                    addr: key.addr,
+                    poll: key.poll,
+                    expires: wall_now + chrono::Duration::from_std(*tat - now).ok()?,
+                })
+            })
+            .collect()
     }
 
-    // Returns true if the address should be rate-limited;
-    // inserts it otherwise
-    pub fn check_vote(&self, addr: IpAddr, poll_id: PollID) -> bool {
-        let mut limits = self.vote.lock().unwrap();
+    /// Adds previously exported limits, skipping the ones that have already expired
+    pub fn import(&self, records: Vec<LimitRecord>) {
         let now = Instant::now();
-        if let Some(instant) = limits.get(&(addr, poll_id.index())) {
-            if now - *instant < self.vote_limit {
-                true
-            } else {
-                limits.remove(&(addr, poll_id.index()));
-                false
+        let wall_now = chrono::Utc::now();
+        let mut limits = self.limits.lock().unwrap();
+        for record in records {
+            let remaining = match (record.expires - wall_now).to_std() {
+                Ok(remaining) if !remaining.is_zero() => remaining,
+                // Negative durations can't be converted
+                _ => continue,
+            };
+            let key = LimitKey {
+                route: record.route,
+                addr: record.addr,
+                poll: record.poll,
+            };
+            let tat = now + remaining;
+            let entry = limits.entry(key).or_insert(tat);
+            *entry = (*entry).max(tat);
+        }
+    }
+
+    /// Checks whether the action should be rate-limited; records it otherwise.
+    /// Returns the time after which the action will be allowed if it's limited.
+    pub fn check(&self, route: Route, addr: IpAddr, poll: Option<PollID>) -> Result<(), Duration> {
+        self.check_at(Instant::now(), route, addr, poll, true)
+    }
+
+    /// Like `check`, but doesn't record the action
+    pub fn peek(&self, route: Route, addr: IpAddr, poll: Option<PollID>) -> Result<(), Duration> {
+        self.check_at(Instant::now(), route, addr, poll, false)
+    }
+
+    fn check_at(
+        &self,
+        now: Instant,
+        route: Route,
+        addr: IpAddr,
+        poll: Option<PollID>,
+        record: bool,
+    ) -> Result<(), Duration> {
+        let policy = self.policies.get(&route).copied().unwrap_or_default();
+        let poll = poll.map(|id| id.index());
+        let checks = [
+            (policy.client, Some(self.aggregate(addr))),
+            (policy.global, None),
+        ];
+
+        let mut limits = self.limits.lock().unwrap();
+        // All limits are checked before any of them is updated,
+        // so that a rejected action doesn't use up any of them
+        let mut updates = Vec::with_capacity(checks.len());
+        for (policy, addr) in checks {
+            let policy = match policy {
+                Some(policy) => policy,
+                None => continue,
+            };
+            let key = LimitKey { route, addr, poll };
+            let tat = limits.get(&key).copied().unwrap_or(now).max(now);
+            let ahead = tat - now;
+            if ahead > policy.tolerance() {
+                return Err(ahead - policy.tolerance());
             }
+            updates.push((key, tat + policy.period));
+        }
+        if record {
+            limits.extend(updates);
+        }
+        Ok(())
+    }
+}
+
+/// Reverse proxies (addresses or CIDR ranges) allowed to report the client's address
+/// through the "Forwarded" or "X-Forwarded-For" header.
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+    /// Only the header actually set by the proxies can be read - the other one is passed
+    /// from the client unchanged and could be spoofed.
+    header: HeaderName,
+}
+
+impl TrustedProxies {
+    /// Parses a comma-separated list of addresses and CIDR ranges, for example:
+    /// `127.0.0.1,10.0.0.0/8,::1`
+    /// `header` has to be either "X-Forwarded-For" or "Forwarded".
+    pub fn parse(list: &str, header: &str) -> anyhow::Result<Self> {
+        let header = if header.eq_ignore_ascii_case(X_FORWARDED_FOR.as_str()) {
+            X_FORWARDED_FOR
+        } else if header.eq_ignore_ascii_case(FORWARDED.as_str()) {
+            FORWARDED
         } else {
-            limits.insert((addr, poll_id.index()), now);
-            false
+            anyhow::bail!("Invalid proxy header: {}", header);
+        };
+
+        let mut nets = Vec::new();
+        for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let net = match entry.parse::<IpNet>() {
+                Ok(net) => net,
+                Err(_) => IpNet::from(
+                    entry
+                        .parse::<IpAddr>()
+                        .map_err(|_| anyhow::anyhow!("Invalid trusted proxy: {}", entry))?,
+                ),
+            };
+            nets.push(net);
         }
+        Ok(TrustedProxies { nets, header })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nets.is_empty()
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(addr))
     }
 }
 
-/// Checks whether a request's address should be rate-limited
-pub fn limit_create(req: &HttpRequest) -> bool {
-    let addr = req.peer_addr();
-    let addr = if let Some(addr) = addr {
-        addr.ip()
+/// Parses a single node of a "Forwarded" header `for=` parameter, for example:
+/// `192.0.2.43`, `"192.0.2.43:47011"`, `"[2001:db8:cafe::17]:4711"`
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(v6) = node.strip_prefix('[') {
+        return v6.split(']').next()?.parse().ok();
+    }
+    match node.parse() {
+        Ok(addr) => Some(addr),
+        // IPv4 with a port
+        Err(_) => node.split(':').next()?.parse().ok(),
+    }
+}
+
+/// Returns the chain of addresses reported by proxies in the given header,
+/// from the client to the last proxy.
+/// Unparseable entries (like "unknown" or obfuscated identifiers) are kept as None.
+fn forwarded_chain(headers: &HeaderMap, header: &HeaderName) -> Vec<Option<IpAddr>> {
+    let elements = headers
+        .get_all(header)
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','));
+
+    if *header == FORWARDED {
+        elements
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_forwarded_node(node))
+            })
+            .collect()
     } else {
-        // TODO: error?
-        return false;
+        elements.map(parse_forwarded_node).collect()
+    }
+}
+
+/// Determines the client's address. Forwarding headers are only honored if the request came
+/// from a trusted proxy; the chain is then walked from the right (the entry added by our proxy)
+/// and the first address that isn't a trusted proxy is returned, so entries made up by the
+/// client itself are never used. If a trusted proxy reports an unparseable address,
+/// the address of that proxy is returned.
+/// Requests without a peer address came through a Unix socket, which only local processes
+/// (the reverse proxy) can connect to, so they're treated as coming from a trusted proxy.
+pub fn resolve_client_ip(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    proxies: &TrustedProxies,
+) -> Option<IpAddr> {
+    if let Some(addr) = peer {
+        if !proxies.contains(&addr) {
+            return Some(addr);
+        }
+    }
+
+    let mut addr = peer;
+    for hop in forwarded_chain(headers, &proxies.header).into_iter().rev() {
+        let Some(hop) = hop else {
+            break;
+        };
+        addr = Some(hop);
+        if !proxies.contains(&hop) {
+            break;
+        }
+    }
+    addr
+}
+
+/// Returns the address a request should be rate-limited by, if any.
+/// Only direct requests from loopback addresses aren't limited. Requests from a trusted proxy
+/// that doesn't report the client are limited by the proxy's address, and requests through
+/// a Unix socket without a forwarding header by the unspecified address, so that each of
+/// them shares a single bucket instead of going unlimited.
+pub fn resolve_limited_addr(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    proxies: Option<&TrustedProxies>,
+) -> Option<IpAddr> {
+    let client = match proxies {
+        Some(proxies) => resolve_client_ip(peer, headers, proxies),
+        None => peer,
+    };
+    let Some(client) = client else {
+        return Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
     };
-    if addr.is_loopback() {
-        return false;
+    let direct = peer == Some(client) && !proxies.is_some_and(|p| p.contains(&client));
+    if direct && client.is_loopback() {
+        return None;
     }
     // rate limit only if the address is globally routable (currently unstable)
     // if(!addr.is_global()) { return false; }
+    Some(client)
+}
+
+fn limited_addr(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let proxies = req.app_data::<web::Data<TrustedProxies>>();
+    resolve_limited_addr(peer, req.headers(), proxies.map(|p| p.as_ref()))
+}
+
+/// Returns the client a request is rate limited as: the network of its address
+/// (see LimitStore::aggregate), or None if it isn't limited (see resolve_limited_addr).
+/// Used to share other per-client limits with the rate limits.
+pub fn client_key(req: &HttpRequest) -> Option<IpAddr> {
+    let addr = limited_addr(req)?;
+    match req.app_data::<web::Data<LimitStore>>() {
+        Some(store) => Some(store.aggregate(addr)),
+        None => Some(addr),
+    }
+}
+
+/// Checks whether a request should be rate-limited on the given route.
+/// PollID must be valid if specified.
+pub fn limit(req: &HttpRequest, route: Route, poll: Option<PollID>) -> Result<(), UserError> {
+    let addr = if let Some(addr) = limited_addr(req) {
+        addr
+    } else {
+        return Ok(());
+    };
     let store = &req.app_data::<web::Data<LimitStore>>().unwrap();
 
-    store.check_create(addr)
+    store.check(route, addr, poll).map_err(|retry_after| {
+        count_rejection(req, route);
+        UserError::TooManyRequests(retry_after)
+    })
 }
 
-/// Checks whether a request's address should be rate-limited
-/// PollID must be valid.
-pub fn limit_vote(req: &HttpRequest, poll_id: PollID) -> bool {
-    let addr = req.peer_addr();
-    let addr = if let Some(addr) = addr {
-        addr.ip()
+/// Checks whether a request is currently rate-limited on the given route, without counting it.
+/// Used together with `limit` to only count failed attempts (like guessing admin tokens).
+pub fn check_limited(req: &HttpRequest, route: Route) -> Result<(), UserError> {
+    let addr = if let Some(addr) = limited_addr(req) {
+        addr
     } else {
-        // TODO: error?
-        return false;
+        return Ok(());
     };
-    if addr.is_loopback() {
-        return false;
-    }
-    // rate limit only if the address is globally routable (currently unstable)
-    // if(!addr.is_global()) { return false; }
     let store = &req.app_data::<web::Data<LimitStore>>().unwrap();
 
-    store.check_vote(addr, poll_id)
+    store.peek(route, addr, None).map_err(|retry_after| {
+        count_rejection(req, route);
+        UserError::TooManyRequests(retry_after)
+    })
+}
+
+fn count_rejection(req: &HttpRequest, route: Route) {
+    if let Some(metrics) = req.app_data::<web::Data<crate::metrics::Metrics>>() {
+        metrics.rate_limited(route);
+    }
+}
+
+#[test]
+fn test_client_ip() {
+    use actix_web::http::header::HeaderValue;
+
+    let proxies = TrustedProxies::parse("127.0.0.1, 10.0.0.0/8, ::1", "X-Forwarded-For").unwrap();
+    let proxy: IpAddr = "127.0.0.1".parse().unwrap();
+    let client: IpAddr = "203.0.113.7".parse().unwrap();
+    let headers = |name, value| {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    };
+
+    // Headers sent directly by a client are ignored
+    let spoofed = headers(X_FORWARDED_FOR, "198.51.100.1");
+    assert_eq!(
+        resolve_client_ip(Some(client), &spoofed, &proxies),
+        Some(client)
+    );
+
+    // The proxy appends the real address after the one made up by the client
+    let appended = headers(X_FORWARDED_FOR, "198.51.100.1, 203.0.113.7");
+    assert_eq!(
+        resolve_client_ip(Some(proxy), &appended, &proxies),
+        Some(client)
+    );
+
+    // Faking a trusted proxy address in the chain doesn't make the client exempt
+    let chain = headers(X_FORWARDED_FOR, "127.0.0.1, 203.0.113.7, 10.1.2.3");
+    assert_eq!(
+        resolve_client_ip(Some(proxy), &chain, &proxies),
+        Some(client)
+    );
+
+    // The header not set by the proxies is passed from the client, so it's ignored
+    let mut both = headers(X_FORWARDED_FOR, "203.0.113.7");
+    both.insert(FORWARDED, HeaderValue::from_static("for=198.51.100.1"));
+    assert_eq!(
+        resolve_client_ip(Some(proxy), &both, &proxies),
+        Some(client)
+    );
+
+    let proxies = TrustedProxies::parse("127.0.0.1", "Forwarded").unwrap();
+    let forwarded = headers(
+        FORWARDED,
+        "for=198.51.100.1;proto=https, for=\"[2001:db8::17]:4711\"",
+    );
+    assert_eq!(
+        resolve_client_ip(Some(proxy), &forwarded, &proxies),
+        Some("2001:db8::17".parse().unwrap())
+    );
+
+    // Garbage in the part of the chain added by trusted proxies
+    let garbage = headers(FORWARDED, "for=unknown");
+    assert_eq!(
+        resolve_client_ip(Some(proxy), &garbage, &proxies),
+        Some(proxy)
+    );
+
+    // Direct requests from the proxy's address itself
+    assert_eq!(
+        resolve_client_ip(Some(proxy), &HeaderMap::new(), &proxies),
+        Some(proxy)
+    );
+
+    // Requests through a Unix socket come from the proxy
+    assert_eq!(
+        resolve_client_ip(None, &forwarded, &proxies),
+        Some("2001:db8::17".parse().unwrap())
+    );
+    assert_eq!(resolve_client_ip(None, &HeaderMap::new(), &proxies), None);
+}
+
+#[test]
+fn test_limited_addr() {
+    use actix_web::http::header::HeaderValue;
+
+    let proxies = TrustedProxies::parse("127.0.0.1, 10.0.0.0/8", "X-Forwarded-For").unwrap();
+    let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+    let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+    let client: IpAddr = "203.0.113.7".parse().unwrap();
+    let unspecified = Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+    let none = HeaderMap::new();
+    let mut forwarded = HeaderMap::new();
+    forwarded.insert(X_FORWARDED_FOR, HeaderValue::from_static("203.0.113.7"));
+
+    assert_eq!(
+        resolve_limited_addr(Some(proxy), &forwarded, Some(&proxies)),
+        Some(client)
+    );
+    // Trusted proxies that don't report the client are limited as a single client
+    assert_eq!(
+        resolve_limited_addr(Some(proxy), &none, Some(&proxies)),
+        Some(proxy)
+    );
+    assert_eq!(
+        resolve_limited_addr(Some(loopback), &none, Some(&proxies)),
+        Some(loopback)
+    );
+    // So are all requests through a Unix socket without a forwarding header
+    assert_eq!(resolve_limited_addr(None, &none, Some(&proxies)), unspecified);
+    assert_eq!(resolve_limited_addr(None, &none, None), unspecified);
+    assert_eq!(
+        resolve_limited_addr(None, &forwarded, Some(&proxies)),
+        Some(client)
+    );
+    // Local requests that don't go through a proxy aren't limited
+    let no_proxies = TrustedProxies::parse("", "X-Forwarded-For").unwrap();
+    assert_eq!(
+        resolve_limited_addr(Some(loopback), &none, Some(&no_proxies)),
+        None
+    );
+    assert_eq!(resolve_limited_addr(Some(loopback), &none, None), None);
+}
+
+#[test]
+fn test_address_aggregation() {
+    let policy = RoutePolicy {
+        client: Some(Policy::new(Duration::from_secs(60), 1)),
+        global: None,
+    };
+    let limits = LimitStore::new(HashMap::from([(Route::Vote, policy)]), 24, 64).unwrap();
+    let poll_id = Some(PollID::new(1, 0));
+    let check = |addr: &str| {
+        limits
+            .check(Route::Vote, addr.parse().unwrap(), poll_id)
+            .is_err()
+    };
+
+    assert!(!check("2001:db8:1:2::1"));
+    assert!(check("2001:db8:1:2:ffff::9"));
+    assert!(!check("2001:db8:1:3::1"));
+
+    assert!(!check("192.0.2.1"));
+    assert!(check("192.0.2.200"));
+    assert!(check("::ffff:192.0.2.7"));
+    assert!(!check("192.0.3.1"));
+
+    assert!(LimitStore::new(HashMap::new(), 33, 64).is_err());
+}
+
+#[test]
+fn test_gcra() {
+    let second = Duration::from_secs(1);
+    let policy = RoutePolicy {
+        client: Some(Policy::new(10 * second, 3)),
+        global: Some(Policy::new(second, 4)),
+    };
+    let limits = LimitStore::new(HashMap::from([(Route::Create, policy)]), 32, 64).unwrap();
+    let a: IpAddr = "192.0.2.1".parse().unwrap();
+    let b: IpAddr = "192.0.2.2".parse().unwrap();
+    let start = Instant::now();
+
+    // A burst of 3 is allowed, then one action per 10 seconds
+    for _ in 0..3 {
+        assert_eq!(limits.check_at(start, Route::Create, a, None, true), Ok(()));
+    }
+    assert_eq!(
+        limits.check_at(start, Route::Create, a, None, true),
+        Err(10 * second)
+    );
+    assert_eq!(
+        limits.check_at(start + 4 * second, Route::Create, a, None, true),
+        Err(6 * second)
+    );
+
+    // Other routes are unlimited
+    assert_eq!(limits.check_at(start, Route::Vote, a, None, true), Ok(()));
+
+    // The global limit is shared by all clients, rejected actions don't count towards it
+    assert_eq!(limits.check_at(start, Route::Create, b, None, true), Ok(()));
+    assert_eq!(
+        limits.check_at(start, Route::Create, b, None, true),
+        Err(second)
+    );
+    assert_eq!(
+        limits.check_at(start + 10 * second, Route::Create, a, None, true),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_export_import() {
+    let policy = RoutePolicy {
+        client: Some(Policy::new(Duration::from_secs(60), 1)),
+        global: Some(Policy::new(Duration::from_secs(1), 1)),
+    };
+    let new_store = || LimitStore::new(HashMap::from([(Route::Vote, policy)]), 32, 64).unwrap();
+    let addr: IpAddr = "192.0.2.1".parse().unwrap();
+    let poll_id = Some(PollID::new(1, 0));
+
+    let limits = new_store();
+    assert!(limits.check(Route::Vote, addr, poll_id).is_ok());
+    let records = limits.export();
+    assert_eq!(records.len(), 2);
+
+    let restored = new_store();
+    restored.import(records);
+    assert!(restored.check(Route::Vote, addr, poll_id).is_err());
 }
@@ -0,0 +1,233 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderValue;
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use futures::future::{ready, Ready};
+
+use crate::error::UserError;
+use crate::session::{self, SessionConfig};
+use crate::util;
+
+/// The name of the cookie holding the browser's CSRF id
+const CSRF_COOKIE: &str = "pollinator_csrf";
+/// The CSRF cookie of the widgets (/embed), which is also sent when they're framed by other
+/// websites. Kept apart so that the rest of the website keeps its SameSite=Lax cookie.
+const EMBED_CSRF_COOKIE: &str = "pollinator_csrf_embed";
+/// The name of the form field (or header) that has to contain the CSRF token
+const CSRF_FIELD: &str = "csrf_token";
+const CSRF_HEADER: &str = "X-CSRF-Token";
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// Inline scripts and styles are used throughout the templates, so they have to be allowed.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'self'; \
+    script-src 'self' 'unsafe-inline'; \
+    style-src 'self' 'unsafe-inline' https://fonts.googleapis.com; \
+    font-src https://fonts.gstatic.com; \
+    img-src 'self' data:; \
+    object-src 'none'; \
+    base-uri 'none'; \
+    form-action 'self'; \
+    frame-ancestors 'none'";
+
+/// Middleware adding security headers to every response (unless a handler sets them itself).
+/// Responses with their own Content-Security-Policy (see EmbedPolicy) can be framed,
+/// so they don't get X-Frame-Options either.
+pub async fn headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let mut res = next.call(req).await?;
+    let headers = res.headers_mut();
+    if !headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+        headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+        );
+        headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    }
+    for (name, value) in [
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        (header::REFERRER_POLICY, "same-origin"),
+    ] {
+        if !headers.contains_key(&name) {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+    }
+    Ok(res)
+}
+
+/// The Content-Security-Policy of pages meant to be embedded in other websites (/embed),
+/// the default one with the configured frame ancestors allowed.
+#[derive(Clone)]
+pub struct EmbedPolicy(String);
+
+impl EmbedPolicy {
+    /// Ancestors are CSP sources, like "https://wiki.example.com" or "*.example.com".
+    /// The server itself is always allowed.
+    pub fn new(ancestors: &[String]) -> anyhow::Result<Self> {
+        let mut sources = vec!["'self'"];
+        for ancestor in ancestors {
+            if ancestor.is_empty()
+                || ancestor.contains(|c: char| c == ';' || c == ',' || c.is_whitespace())
+            {
+                anyhow::bail!("Invalid embed ancestor: {:?}", ancestor);
+            }
+            sources.push(ancestor);
+        }
+        Ok(EmbedPolicy(CONTENT_SECURITY_POLICY.replace(
+            "frame-ancestors 'none'",
+            &format!("frame-ancestors {}", sources.join(" ")),
+        )))
+    }
+
+    /// A HTML response that can be framed by the allowed ancestors
+    pub fn response(&self) -> HttpResponseBuilder {
+        let mut response = HttpResponse::Ok();
+        response
+            .content_type("text/html; charset=utf-8")
+            .insert_header((header::CONTENT_SECURITY_POLICY, self.0.as_str()));
+        response
+    }
+}
+
+/// The CSRF token that has to be submitted with every form on the website.
+/// Derived from a random id stored in a cookie, signed with the session key.
+#[derive(Clone)]
+pub struct CsrfToken(pub String);
+
+impl FromRequest for CsrfToken {
+    type Error = UserError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<CsrfToken>()
+                .cloned()
+                .ok_or_else(|| UserError::InternalError(anyhow::anyhow!("CSRF token not set"))),
+        )
+    }
+}
+
+/// Returns the token from an "Authorization: Bearer {token}" header
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// The message signed to create a CSRF token out of the cookie id
+fn csrf_message(cookie: &str, id: &str) -> String {
+    format!("{}.{}", cookie, id)
+}
+
+/// Removes the CSRF token field from an urlencoded form, returns the token and the remaining form
+fn take_form_token(form: &str) -> (Option<&str>, String) {
+    util::take_form_field(form, CSRF_FIELD)
+}
+
+/// Middleware protecting all state-changing requests against cross-site request forgery.
+/// Every browser gets a random id in a cookie; forms have to include a token derived from it
+/// (see CsrfToken) in a `csrf_token` field or an `X-CSRF-Token` header.
+/// The field is removed from urlencoded bodies before they reach the handlers.
+/// The widgets use their own cookie, see EMBED_CSRF_COOKIE.
+/// Requests with an "Authorization: Bearer" header (used by scripts) are not checked,
+/// since browsers never attach it to cross-site requests on their own.
+pub async fn protect(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let sessions = req
+        .app_data::<web::Data<SessionConfig>>()
+        .expect("SessionConfig not registered")
+        .clone();
+
+    let embed = req.path().starts_with("/embed/");
+    let cookie_name = if embed { EMBED_CSRF_COOKIE } else { CSRF_COOKIE };
+    let (id, new_cookie) = match req.cookie(cookie_name) {
+        Some(cookie) => (cookie.value().to_string(), false),
+        None => (util::random_base64_u64(), true),
+    };
+
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if !safe && bearer_token(req.request()).is_none() {
+        let header_token = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string);
+
+        let token = match header_token {
+            Some(token) => Some(token),
+            None if req.content_type() == FORM_CONTENT_TYPE => {
+                let body = match req.extract::<web::Bytes>().await {
+                    Ok(body) => body,
+                    Err(e) => return Ok(req.error_response(e).map_into_right_body()),
+                };
+                let Ok(body) = std::str::from_utf8(&body) else {
+                    return Ok(req
+                        .error_response(UserError::InvalidCsrfToken)
+                        .map_into_right_body());
+                };
+                let (token, rest) = take_form_token(body);
+                let token = token.map(str::to_string);
+                req.set_payload(Payload::from(web::Bytes::from(rest)));
+                token
+            }
+            None => None,
+        };
+
+        // Rejections are returned as responses rather than errors, so that the outer
+        // middleware (security headers, request IDs) still applies to them
+        match token {
+            Some(token) if !new_cookie && sessions.key.verify(&csrf_message(cookie_name, &id), &token) => (),
+            _ => {
+                return Ok(req
+                    .error_response(UserError::InvalidCsrfToken)
+                    .map_into_right_body())
+            }
+        }
+    }
+
+    req.extensions_mut()
+        .insert(CsrfToken(sessions.key.sign(&csrf_message(cookie_name, &id))));
+
+    let mut res = next.call(req).await?;
+    if new_cookie {
+        let mut cookie = Cookie::build(cookie_name, id)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .finish();
+        if embed {
+            session::allow_cross_site(&mut cookie, res.request());
+        }
+        res.response_mut().add_cookie(&cookie)?;
+    }
+    Ok(res.map_into_left_body())
+}
+
+#[test]
+fn test_take_form_token() {
+    let (token, rest) = take_form_token("csrf_token=abc&response=1&response=2");
+    assert_eq!(token, Some("abc"));
+    assert_eq!(rest, "response=1&response=2");
+
+    let (token, rest) = take_form_token("0=1&1=0");
+    assert_eq!(token, None);
+    assert_eq!(rest, "0=1&1=0");
+}
+
+#[test]
+fn test_embed_policy() {
+    let policy = EmbedPolicy::new(&["https://wiki.example.com".into()]).unwrap();
+    assert!(policy
+        .0
+        .ends_with("frame-ancestors 'self' https://wiki.example.com"));
+    assert!(EmbedPolicy::new(&["https://a.com; script-src *".into()]).is_err());
+}
@@ -0,0 +1,206 @@
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::HttpRequest;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use std::time::Duration;
+
+use crate::poll::PollID;
+use crate::util;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The name of the cookie holding the general administration session
+const SERVER_COOKIE: &str = "pollinator_admin";
+/// The prefix of the cookies marking polls the browser has voted on
+const VOTER_COOKIE: &str = "pollinator_voted";
+/// How long a browser is remembered as having voted on a poll (in seconds)
+const VOTER_COOKIE_AGE: i64 = 365 * 24 * 60 * 60;
+
+/// Admin session settings
+pub struct SessionConfig {
+    pub key: SessionKey,
+    /// How long a session lasts after logging in
+    pub length: Duration,
+}
+
+/// Secret key used to sign session and voter cookies.
+/// Read from the "POLL_SESSION_KEY" environmental variable. If it's not set, the server
+/// generates one and keeps it in the database (see db::session_key), since a key changing
+/// on restart would log out all admins and let browsers vote again on dedupe polls.
+pub struct SessionKey(Vec<u8>);
+
+impl SessionKey {
+    /// Uses the given key, or a random one if there's none (only lasting until the restart)
+    pub fn new(key: Option<String>) -> Self {
+        SessionKey(key.unwrap_or_else(SessionKey::generate).into_bytes())
+    }
+
+    /// Generates a random key, as text so that it can be stored
+    pub fn generate() -> String {
+        base64::encode_engine(rand::thread_rng().gen::<[u8; 32]>(), &util::BASE64_ENGINE)
+    }
+
+    fn mac(&self, message: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC accepts keys of any size");
+        mac.update(message.as_bytes());
+        mac
+    }
+
+    /// Returns the base64-encoded signature of a message
+    pub fn sign(&self, message: &str) -> String {
+        let signature = self.mac(message).finalize().into_bytes();
+        base64::encode_engine(signature, &util::BASE64_ENGINE)
+    }
+
+    /// Checks a signature produced by `sign` (in constant time)
+    pub fn verify(&self, message: &str, signature: &str) -> bool {
+        match base64::decode_engine(signature, &util::BASE64_ENGINE) {
+            Ok(signature) => self.mac(message).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// What an admin session allows to manage
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SessionScope {
+    /// The whole server (logged in with the POLL_ADMIN_TOKEN)
+    Server,
+    /// A single poll with the given index (logged in with the poll's admin token)
+    Poll(usize),
+}
+
+impl SessionScope {
+    pub fn cookie_name(&self) -> String {
+        match self {
+            SessionScope::Server => SERVER_COOKIE.to_string(),
+            SessionScope::Poll(index) => format!("{}_{}", SERVER_COOKIE, index),
+        }
+    }
+}
+
+/// A logged in administrator, stored in a signed cookie in format:
+/// `{expires}.{signature}`
+/// The signature also covers the cookie name and the admin token the session was created with,
+/// so changing the token invalidates all of its sessions.
+#[derive(Debug)]
+pub struct AdminSession {
+    pub scope: SessionScope,
+    /// Unix timestamp after which the session is no longer valid
+    pub expires: i64,
+}
+
+impl AdminSession {
+    pub fn new(scope: SessionScope, length: Duration) -> Self {
+        AdminSession {
+            scope,
+            expires: chrono::Utc::now().timestamp() + length.as_secs() as i64,
+        }
+    }
+
+    /// Reads and verifies the session cookie of the given scope.
+    /// `admin_token` is the token currently valid for that scope.
+    pub fn from_request(
+        req: &HttpRequest,
+        key: &SessionKey,
+        scope: SessionScope,
+        admin_token: &str,
+    ) -> Option<Self> {
+        let name = scope.cookie_name();
+        let cookie = req.cookie(&name)?;
+        let (expires, signature) = cookie.value().split_once('.')?;
+        if !key.verify(&format!("{}.{}.{}", name, expires, admin_token), signature) {
+            return None;
+        }
+        let expires: i64 = expires.parse().ok()?;
+        if expires < chrono::Utc::now().timestamp() {
+            return None;
+        }
+
+        Some(AdminSession { scope, expires })
+    }
+
+    /// Creates a signed HttpOnly cookie holding this session
+    pub fn cookie(&self, key: &SessionKey, admin_token: &str) -> Cookie<'static> {
+        let name = self.scope.cookie_name();
+        // The admin token is only a part of the signed message, it is not sent to the client
+        let signature = key.sign(&format!("{}.{}.{}", name, self.expires, admin_token));
+        let value = format!("{}.{}", self.expires, signature);
+
+        let max_age = self.expires - chrono::Utc::now().timestamp();
+        Cookie::build(name, value)
+            .path("/admin")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .max_age(actix_web::cookie::time::Duration::seconds(max_age))
+            .finish()
+    }
+
+    /// Creates a cookie that removes the session of the given scope from the client
+    pub fn removal_cookie(scope: SessionScope) -> Cookie<'static> {
+        let mut cookie = Cookie::build(scope.cookie_name(), "")
+            .path("/admin")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .finish();
+        cookie.make_removal();
+        cookie
+    }
+}
+
+/// Marks a browser that has voted on a poll with duplicate vote detection turned on
+/// (see PollSettings), stored in a signed cookie in format: `{voted_at}.{signature}`
+/// The signature covers the full poll id, so the cookie can't be reused for a different poll
+/// (even one with the same index). Clearing cookies is enough to vote again, so it should be
+/// combined with the IP rate limits.
+pub struct VoterCookie;
+
+impl VoterCookie {
+    fn name(poll_id: PollID) -> String {
+        format!("{}_{}", VOTER_COOKIE, poll_id.index())
+    }
+
+    /// Checks whether the request carries a valid cookie for the poll
+    pub fn has_voted(req: &HttpRequest, key: &SessionKey, poll_id: PollID) -> bool {
+        let name = Self::name(poll_id);
+        let cookie = match req.cookie(&name) {
+            Some(cookie) => cookie,
+            None => return false,
+        };
+        match cookie.value().split_once('.') {
+            Some((voted_at, signature)) => {
+                key.verify(&format!("{}.{}.{}", name, voted_at, poll_id), signature)
+            }
+            None => false,
+        }
+    }
+
+    /// Creates the cookie. It's sent with every request, so that it also covers
+    /// the poll's voting widget (/embed/vote/{poll_id}), also when it's framed by
+    /// another website (see allow_cross_site).
+    pub fn cookie(req: &HttpRequest, key: &SessionKey, poll_id: PollID) -> Cookie<'static> {
+        let name = Self::name(poll_id);
+        let voted_at = chrono::Utc::now().timestamp();
+        let signature = key.sign(&format!("{}.{}.{}", name, voted_at, poll_id));
+
+        let mut cookie = Cookie::build(name, format!("{}.{}", voted_at, signature))
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(actix_web::cookie::time::Duration::seconds(VOTER_COOKIE_AGE))
+            .finish();
+        allow_cross_site(&mut cookie, req);
+        cookie
+    }
+}
+
+/// Lets browsers send a cookie from widgets framed by other websites (SameSite=None).
+/// They only accept that on Secure cookies, so it's only done for HTTPS requests;
+/// over plain HTTP the cookie stays Lax and the widgets only work on this website.
+pub fn allow_cross_site(cookie: &mut Cookie<'_>, req: &HttpRequest) {
+    if req.connection_info().scheme() == "https" {
+        cookie.set_same_site(SameSite::None);
+        cookie.set_secure(true);
+    }
+}
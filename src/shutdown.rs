@@ -0,0 +1,40 @@
+//! Graceful shutdown on SIGTERM and SIGINT: new votes are refused, requests in progress
+//! are finished and the state kept in memory is written to the database before exiting.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once the server starts shutting down
+#[derive(Debug, Default)]
+pub struct Shutdown(AtomicBool);
+
+impl Shutdown {
+    pub fn begin(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Waits until the process is asked to stop, returns the name of the signal
+#[cfg(unix)]
+pub async fn signal() -> std::io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let terminate = Box::pin(terminate.recv());
+    let interrupt = Box::pin(interrupt.recv());
+    let name = match futures::future::select(terminate, interrupt).await {
+        futures::future::Either::Left(_) => "SIGTERM",
+        futures::future::Either::Right(_) => "SIGINT",
+    };
+    Ok(name)
+}
+
+/// Waits until the process is asked to stop, returns the name of the signal
+#[cfg(not(unix))]
+pub async fn signal() -> std::io::Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
@@ -1,4 +1,6 @@
-use crate::poll::PollID;
+use crate::poll::{PollData, PollID};
+use crate::session::AdminSession;
+use crate::webhook::{Delivery, Webhook};
 use askama::Template;
 
 #[derive(Template)]
@@ -19,11 +21,30 @@ pub struct PollCreatedTemplate<'a> {
     pub admin_token: &'a str,
 }
 
+#[derive(Template)]
+#[template(path = "token_regenerated.html")]
+/// Returned when a poll's admin token was replaced with a new one.
+pub struct TokenRegeneratedTemplate<'a> {
+    pub admin_link: &'a str,
+    pub admin_token: &'a str,
+}
+
 #[derive(Template)]
 #[template(path = "voted.html")]
 /// Returned when a vote was successfully registered.
 pub struct VotedTemplate<'a> {
     pub results_link: &'a str,
+    /// Shown in the voting widget, see embed.rs
+    pub embed: bool,
+}
+
+#[derive(Template)]
+#[template(path = "embed_results.html")]
+/// The results widget, see embed.rs
+pub struct EmbedResultsTemplate<'a> {
+    pub poll: &'a PollData,
+    pub results_link: &'a str,
+    pub vote_link: &'a str,
 }
 
 /// All essential poll information - to be displayed in a poll list
@@ -42,3 +63,42 @@ pub struct PollInfo {
 pub struct PollListTemplate {
     pub polls: Vec<PollInfo>,
 }
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+/// The general administration page, shows a login form if there is no session
+pub struct AdminTemplate<'a> {
+    pub session: Option<&'a AdminSession>,
+    pub csrf_token: &'a str,
+}
+
+/// A record of a change made by a poll admin
+pub struct AuditEntry {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub action: String,
+    pub details: String,
+}
+
+impl AuditEntry {
+    pub fn new(action: &str, details: String) -> Self {
+        AuditEntry {
+            time: chrono::Utc::now(),
+            action: action.to_string(),
+            details,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "poll_admin.html")]
+/// The poll-specific administration page, shows a login form if there is no session
+pub struct PollAdminTemplate<'a> {
+    pub poll: &'a PollData,
+    pub session: Option<&'a AdminSession>,
+    pub csrf_token: &'a str,
+    /// Only shown when logged in
+    pub audit_log: &'a [AuditEntry],
+    /// None if webhooks are disabled on the server
+    pub webhooks: Option<&'a [Webhook]>,
+    pub deliveries: &'a [Delivery],
+}
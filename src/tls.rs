@@ -0,0 +1,74 @@
+//! Serving HTTPS directly, without a reverse proxy. The certificate and key are read from
+//! PEM files and can be replaced while the server is running, see CertStore::reload.
+
+use anyhow::Context;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// The certificate served on every TLS connection
+#[derive(Debug)]
+pub struct CertStore {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertStore {
+    pub fn new(cert_path: &Path, key_path: &Path) -> anyhow::Result<Self> {
+        Ok(CertStore {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(Arc::new(load(cert_path, key_path)?)),
+        })
+    }
+
+    /// Reads the files again, e.g. after the certificate was renewed.
+    /// The old certificate is kept if the new one can't be loaded.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let key = load(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    /// The configuration of the TLS listeners, which always use the current certificate
+    pub fn server_config(self: &Arc<Self>) -> anyhow::Result<rustls::ServerConfig> {
+        Ok(
+            rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_cert_resolver(self.clone()),
+        )
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Reads a certificate chain and its private key, checking that they match
+fn load(cert_path: &Path, key_path: &Path) -> anyhow::Result<CertifiedKey> {
+    let open = |path: &Path| {
+        std::fs::File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("Failed to read {:?}", path))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate file {:?}", cert_path))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates in {:?}", cert_path);
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .with_context(|| format!("Invalid key file {:?}", key_path))?
+        .with_context(|| format!("No private key in {:?}", key_path))?;
+
+    let provider: &CryptoProvider = &ring::default_provider();
+    CertifiedKey::from_der(certs, key, provider)
+        .context("The private key can't be used with the certificate")
+}
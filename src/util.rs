@@ -22,6 +22,39 @@ pub fn get_env_duration_or(name: &str, default: Duration) -> anyhow::Result<Dura
         .unwrap_or(Ok(default))
 }
 
+/// Tries to retrieve a number from an environmental variable or returns a default.
+pub fn get_env_number_or<T: std::str::FromStr>(name: &str, default: T) -> anyhow::Result<T> {
+    match std::env::var(name) {
+        Ok(n) => n.parse().map_err(|_| anyhow!("{}, must be a number", name)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Checks whether a flag is enabled by an environmental variable ("1", "true", "yes" or "on")
+/// or returns a default if it's not set.
+pub fn get_env_flag_or(name: &str, default: bool) -> bool {
+    std::env::var(name)
+        .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(default)
+}
+
+/// Removes a field from an urlencoded form, returns its (still encoded) value and the remaining
+/// form. Used for fields that are checked before the form reaches the poll format.
+pub fn take_form_field<'a>(form: &'a str, name: &str) -> (Option<&'a str>, String) {
+    let mut value = None;
+    let rest: Vec<&str> = form
+        .split('&')
+        .filter(|field| match field.split_once('=') {
+            Some((field_name, field_value)) if field_name == name => {
+                value = Some(field_value);
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    (value, rest.join("&"))
+}
+
 /// Parses poll options in format:
 /// {0}={p}&{1}={p}&...,{n-1}={p}&{n}={p}
 /// 0,1...n - the option index
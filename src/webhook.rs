@@ -0,0 +1,419 @@
+//! Outgoing webhooks: poll admins register URLs that get a signed JSON payload
+//! whenever something happens to the poll. Failed deliveries are retried a few times,
+//! and every attempt is recorded in the poll's delivery log. Every webhook's events are
+//! delivered in order by a single worker, which runs while it has events queued.
+
+use anyhow::{anyhow, Context};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::db::{self, DbPool};
+use crate::export::PollResults;
+use crate::poll::{Poll, PollID};
+use crate::util;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The most webhooks a single poll can have
+pub const MAX_WEBHOOKS: usize = 5;
+/// The number of delivery log entries kept for every poll
+pub const DELIVERY_LOG_LENGTH: usize = 50;
+/// The time to wait before each retry of a failed delivery
+const RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_secs(10),
+    Duration::from_secs(60),
+    Duration::from_secs(10 * 60),
+];
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// The most events waiting for delivery to a single webhook, newer ones are dropped
+const QUEUE_LENGTH: usize = 20;
+/// The size of signing secrets (in bytes)
+const SECRET_LENGTH: usize = 32;
+
+/// Something that happened to a poll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A vote was cast
+    Vote,
+    /// Ballots were imported by the poll's admin
+    Import,
+    /// The poll's admin reset all votes
+    Reset,
+    /// The poll was deleted. The payload has the poll's last results.
+    Delete,
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Event::Vote => "vote",
+            Event::Import => "import",
+            Event::Reset => "reset",
+            Event::Delete => "delete",
+        })
+    }
+}
+
+/// A URL registered by a poll's admin
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    /// The key of the payload signatures, shown to the poll's admin
+    pub secret: String,
+}
+
+/// An attempt at delivering an event, see db::log_delivery
+pub struct Delivery {
+    pub url: String,
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub event: String,
+    /// Starting from 1
+    pub attempt: u32,
+    /// The response's status code or the reason the request failed
+    pub result: String,
+}
+
+/// The JSON body of every webhook request
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: String,
+    /// RFC 3339
+    time: String,
+    poll: PollResults<'a>,
+}
+
+/// An event waiting for delivery to a webhook
+struct Job {
+    webhook: Webhook,
+    poll_id: PollID,
+    event: Event,
+    body: String,
+    /// Where the attempts are logged, None if they're not (see Webhooks::notify)
+    log: Option<DbPool>,
+}
+
+/// Sends the webhooks of polls, see Webhooks::notify
+#[derive(Clone)]
+pub struct Webhooks {
+    /// Whether webhooks can be sent to loopback and private network addresses
+    allow_private: bool,
+    /// The queues of the webhooks that have a worker running, by webhook id
+    queues: Arc<Mutex<HashMap<i64, VecDeque<Job>>>>,
+}
+
+impl Webhooks {
+    pub fn new(allow_private: bool) -> Self {
+        Webhooks {
+            allow_private,
+            queues: Arc::default(),
+        }
+    }
+
+    /// Checks that a webhook URL can be registered: only http(s) addresses
+    /// that resolve to allowed IP addresses.
+    pub async fn check_url(&self, url: &str) -> anyhow::Result<()> {
+        let url = reqwest::Url::parse(url).context("Invalid URL")?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(anyhow!("Only http and https URLs are allowed"));
+        }
+        self.resolve(&url).await.map(|_| ())
+    }
+
+    /// Sends the event to all of the poll's webhooks in the background.
+    /// Deliveries of deleted polls aren't logged, since the log is deleted with the poll.
+    pub async fn notify(&self, pool: &DbPool, poll: &Poll, event: Event) {
+        match db::get_webhooks(pool, poll.data.id).await {
+            Ok(webhooks) => self.send_all(pool, poll, event, webhooks),
+            Err(e) => log::error!(
+                "Failed to load the webhooks of poll id {}: {}",
+                poll.data.id,
+                e
+            ),
+        }
+    }
+
+    /// Like notify, with the webhooks already loaded (e.g. before the poll was deleted)
+    pub fn send_all(&self, pool: &DbPool, poll: &Poll, event: Event, webhooks: Vec<Webhook>) {
+        if webhooks.is_empty() {
+            return;
+        }
+        let payload = Payload {
+            event: event.to_string(),
+            time: chrono::Utc::now().to_rfc3339(),
+            poll: PollResults::new(poll),
+        };
+        // Serializing numbers and strings can't fail
+        let body = serde_json::to_string(&payload).unwrap_or_default();
+        let log = (event != Event::Delete).then(|| pool.clone());
+
+        for webhook in webhooks {
+            self.enqueue(Job {
+                webhook,
+                poll_id: poll.data.id,
+                event,
+                body: body.clone(),
+                log: log.clone(),
+            });
+        }
+    }
+
+    /// Adds the job to its webhook's queue, starting a worker if there isn't one
+    fn enqueue(&self, job: Job) {
+        let mut queues = self.queues.lock().unwrap();
+        let id = job.webhook.id;
+        let queue = queues.entry(id).or_insert_with(|| {
+            let webhooks = self.clone();
+            actix_web::rt::spawn(async move { webhooks.work(id).await });
+            VecDeque::new()
+        });
+        if queue.len() >= QUEUE_LENGTH {
+            log::warn!(
+                "Webhook {} of poll id {}: too many pending deliveries, {} event dropped",
+                id,
+                job.poll_id,
+                job.event
+            );
+            return;
+        }
+        queue.push_back(job);
+    }
+
+    /// Delivers a webhook's jobs one by one, until its queue is empty
+    async fn work(&self, id: i64) {
+        loop {
+            let job = {
+                let mut queues = self.queues.lock().unwrap();
+                match queues.get_mut(&id).and_then(VecDeque::pop_front) {
+                    Some(job) => job,
+                    // Jobs are only queued while the queue is in the map, so none can be lost
+                    None => {
+                        queues.remove(&id);
+                        return;
+                    }
+                }
+            };
+            self.deliver(job).await;
+        }
+    }
+
+    /// Sends a payload until it succeeds, runs out of retries or the webhook is removed
+    async fn deliver(&self, job: Job) {
+        let Job {
+            webhook,
+            poll_id,
+            event,
+            body,
+            log,
+        } = job;
+        for attempt in 1..=RETRY_DELAYS.len() as u32 + 1 {
+            let result = self.send(&webhook, event, &body).await;
+            let delivered = matches!(result, Ok(status) if status.is_success());
+            let result = match result {
+                Ok(status) => status.to_string(),
+                Err(e) => format!("{:#}", e),
+            };
+            match &log {
+                Some(pool) => {
+                    let delivery = Delivery {
+                        url: webhook.url.clone(),
+                        time: chrono::Utc::now(),
+                        event: event.to_string(),
+                        attempt,
+                        result,
+                    };
+                    match db::log_delivery(pool, poll_id, webhook.id, &delivery).await {
+                        Ok(true) => {}
+                        Ok(false) => return,
+                        Err(e) => log::error!("Failed to log a webhook delivery: {}", e),
+                    }
+                }
+                None => log::info!(
+                    "Webhook {} of poll id {}: {} event, attempt {}: {}",
+                    webhook.id,
+                    poll_id,
+                    event,
+                    attempt,
+                    result
+                ),
+            }
+            if delivered {
+                return;
+            }
+            if let Some(delay) = RETRY_DELAYS.get(attempt as usize - 1) {
+                actix_web::rt::time::sleep(*delay).await;
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        webhook: &Webhook,
+        event: Event,
+        body: &str,
+    ) -> anyhow::Result<reqwest::StatusCode> {
+        let url = reqwest::Url::parse(&webhook.url)?;
+        // Connect to the checked address, so that DNS can't point somewhere else in the meantime
+        let address = self.resolve(&url).await?;
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve(url.host_str().unwrap_or_default(), address)
+            .build()?;
+        let response = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(reqwest::header::USER_AGENT, "Pollinator webhooks")
+            .header("X-Pollinator-Event", event.to_string())
+            .header("X-Pollinator-Signature", signature(&webhook.secret, body))
+            .body(body.to_string())
+            .send()
+            .await?;
+        Ok(response.status())
+    }
+
+    /// Resolves the URL's host to an address webhooks can be sent to
+    async fn resolve(&self, url: &reqwest::Url) -> anyhow::Result<SocketAddr> {
+        let host = url.host_str().context("The URL has no host")?.to_string();
+        let port = url.port_or_known_default().context("The URL has no port")?;
+        let addresses: Vec<SocketAddr> =
+            actix_web::web::block(move || (host.trim_matches(['[', ']']), port).to_socket_addrs())
+                .await?
+                .context("Failed to resolve the URL's host")?
+                .collect();
+        let address = addresses
+            .first()
+            .context("The URL's host has no addresses")?;
+        if !self.allow_private && !addresses.iter().all(|a| is_public(a.ip())) {
+            return Err(anyhow!("Webhooks can't be sent to private addresses"));
+        }
+        Ok(*address)
+    }
+}
+
+/// A new random signing secret
+pub fn generate_secret() -> String {
+    let mut secret = [0; SECRET_LENGTH];
+    rand::thread_rng().fill_bytes(&mut secret);
+    base64::encode_engine(secret, &util::BASE64_ENGINE)
+}
+
+/// The signature of a payload: "sha256=" and the hex-encoded HMAC-SHA256 of the body
+pub fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+/// Whether an address is reachable on the internet, rather than in a private network
+/// or on the server itself
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "This network" (0.0.0.0/8)
+                || first == 0
+                // Shared address space (RFC 6598)
+                || (first == 100 && second & 0xc0 == 64)
+                // Benchmarking (198.18.0.0/15)
+                || (first == 198 && second & 0xfe == 18)
+                // Reserved (240.0.0.0/4), including the broadcast address
+                || first >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // IPv4-compatible addresses (::a.b.c.d)
+                    || segments[..6] == [0; 6]
+                    // NAT64 (64:ff9b::/96 and the local-use 64:ff9b:1::/48)
+                    || segments[..2] == [0x64, 0xff9b]
+                    // 6to4 (2002::/16) and Teredo (2001::/32), which embed IPv4 addresses
+                    || segments[0] == 0x2002
+                    || segments[..2] == [0x2001, 0]
+                    // Documentation (2001:db8::/32)
+                    || segments[..2] == [0x2001, 0xdb8]
+                    // Unique local (fc00::/7) and link-local (fe80::/10) addresses
+                    || segments[0] & 0xfe00 == 0xfc00
+                    || segments[0] & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+#[test]
+fn test_webhooks() {
+    // The example from GitHub's webhook documentation
+    assert_eq!(
+        signature("It's a Secret to Everybody", "Hello, World!"),
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+    );
+    let secret = base64::decode_engine(generate_secret(), &util::BASE64_ENGINE).unwrap();
+    assert_eq!(secret.len(), SECRET_LENGTH);
+    assert_ne!(generate_secret(), generate_secret());
+}
+
+#[test]
+fn test_is_public() {
+    for (ip, public) in [
+        ("1.1.1.1", true),
+        ("100.128.0.1", true),
+        ("198.20.0.1", true),
+        ("223.255.255.255", true),
+        ("2606:4700::1111", true),
+        ("::ffff:1.1.1.1", true),
+        ("127.0.0.1", false),
+        ("10.1.2.3", false),
+        ("172.16.0.1", false),
+        ("192.168.0.1", false),
+        ("169.254.1.1", false),
+        ("100.64.0.1", false),
+        ("192.0.2.1", false),
+        ("0.0.0.0", false),
+        ("0.1.2.3", false),
+        ("224.0.0.1", false),
+        ("239.255.255.250", false),
+        ("198.18.0.1", false),
+        ("198.19.255.255", false),
+        ("240.0.0.1", false),
+        ("255.255.255.255", false),
+        ("::", false),
+        ("::1", false),
+        ("fd00::1", false),
+        ("fe80::1", false),
+        ("ff02::1", false),
+        ("ff0e::1", false),
+        ("::ffff:10.0.0.1", false),
+        ("::127.0.0.1", false),
+        ("::10.0.0.1", false),
+        ("64:ff9b::7f00:1", false),
+        ("64:ff9b:1::a00:1", false),
+        ("2002:7f00:1::1", false),
+        ("2002:a00:1::1", false),
+        ("2001:0:4136:e378:8000:63bf:f5ff:fffe", false),
+        ("2001:db8::1", false),
+    ] {
+        assert_eq!(is_public(ip.parse().unwrap()), public, "{}", ip);
+    }
+}
@@ -0,0 +1,76 @@
+// Updates a results page in place whenever the server sends new results
+// (see handle_results_events in src/main.rs). The page describes how to show them:
+//  - describe(tally, tallies, results) returns the width of an option's meter
+//    (in percent) and the text next to it
+//  - summary(tallies, results) returns the lines of the #voters_count paragraph
+// `tallies` are the tallies of all options, `results` is the whole event
+// (the same as /results/{poll_id}.json).
+
+function liveResults(pollId, describe, summary) {
+    if (!window.EventSource) {
+        return;
+    }
+    const source = new EventSource(`/results/${pollId}/events`);
+    source.addEventListener("results", (event) => {
+        const results = JSON.parse(event.data);
+        const tallies = results.options.map((option) => option.tally);
+        const max = Math.max(...tallies);
+        const options = [...results.options].sort((a, b) => b.tally - a.tally);
+
+        const rows = options.map((option, index) =>
+            liveResultsRow(option, index, max, describe(option.tally, tallies, results)));
+        document.querySelector("#poll_results table").replaceChildren(...rows);
+
+        const lines = summary(tallies, results).flatMap((line) => [line, document.createElement("br")]);
+        lines.pop();
+        document.getElementById("voters_count").replaceChildren(...lines);
+
+        const chart = document.getElementById("results_chart");
+        if (chart) {
+            const url = new URL(chart.src);
+            url.searchParams.set("voters", results.voters);
+            chart.src = url;
+        }
+    });
+}
+
+// The same markup as the rows rendered by the results templates
+function liveResultsRow(option, index, max, view) {
+    const row = document.createElement("tr");
+    row.className = "result_entry";
+
+    const label = document.createElement("label");
+    label.htmlFor = `opt${index}`;
+    label.textContent = option.name;
+
+    const meter = document.createElement("div");
+    meter.id = `opt${index}`;
+    meter.className = "meter";
+    meter.setAttribute("role", "meter");
+    meter.setAttribute("aria-valuenow", option.tally);
+    meter.setAttribute("aria-valuemin", 0);
+    meter.setAttribute("aria-valuemax", max);
+    meter.setAttribute("aria-label", "vote number");
+    const svgNs = "http://www.w3.org/2000/svg";
+    const fill = document.createElementNS(svgNs, "svg");
+    fill.setAttribute("width", `${view.width}%`);
+    fill.setAttribute("class", "meter-fill");
+    fill.setAttribute("aria-hidden", "true");
+    const rect = document.createElementNS(svgNs, "rect");
+    for (const [name, value] of [["x", 0], ["y", 0], ["width", "100%"], ["height", "100%"], ["fill", "currentColor"]]) {
+        rect.setAttribute(name, value);
+    }
+    fill.append(rect);
+    meter.append(fill);
+
+    const numbers = document.createElement("span");
+    numbers.id = "result_numbers";
+    numbers.textContent = view.text;
+
+    for (const content of [label, meter, numbers]) {
+        const cell = document.createElement("td");
+        cell.append(content);
+        row.append(cell);
+    }
+    return row;
+}
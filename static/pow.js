@@ -0,0 +1,128 @@
+// Solves the proof of work challenges issued by the server (see src/pow.rs).
+// A solution is a number `n` such that SHA-256("{challenge}.{n}") starts with
+// at least as many zero bits as the difficulty stated in the challenge.
+// Plain JavaScript SHA-256 is used, since crypto.subtle is only available over HTTPS.
+
+const POW_K = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+function powRotr(x, n) {
+    return (x >>> n) | (x << (32 - n));
+}
+
+// Returns the SHA-256 hash of an ASCII string as 8 32-bit words
+function powSha256(ascii) {
+    const bytes = [];
+    for (let i = 0; i < ascii.length; i++) {
+        bytes.push(ascii.charCodeAt(i) & 0xff);
+    }
+    const bitLength = bytes.length * 8;
+    bytes.push(0x80);
+    while (bytes.length % 64 != 56) {
+        bytes.push(0);
+    }
+    // Messages are short, so the upper 32 bits of the length are always 0
+    bytes.push(0, 0, 0, 0);
+    for (let i = 3; i >= 0; i--) {
+        bytes.push((bitLength >>> (i * 8)) & 0xff);
+    }
+
+    const h = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    const w = new Array(64);
+    for (let offset = 0; offset < bytes.length; offset += 64) {
+        for (let i = 0; i < 16; i++) {
+            const j = offset + i * 4;
+            w[i] = (bytes[j] << 24) | (bytes[j + 1] << 16) | (bytes[j + 2] << 8) | bytes[j + 3];
+        }
+        for (let i = 16; i < 64; i++) {
+            const s0 = powRotr(w[i - 15], 7) ^ powRotr(w[i - 15], 18) ^ (w[i - 15] >>> 3);
+            const s1 = powRotr(w[i - 2], 17) ^ powRotr(w[i - 2], 19) ^ (w[i - 2] >>> 10);
+            w[i] = (w[i - 16] + s0 + w[i - 7] + s1) | 0;
+        }
+
+        let [a, b, c, d, e, f, g, hh] = h;
+        for (let i = 0; i < 64; i++) {
+            const s1 = powRotr(e, 6) ^ powRotr(e, 11) ^ powRotr(e, 25);
+            const ch = (e & f) ^ (~e & g);
+            const t1 = (hh + s1 + ch + POW_K[i] + w[i]) | 0;
+            const s0 = powRotr(a, 2) ^ powRotr(a, 13) ^ powRotr(a, 22);
+            const maj = (a & b) ^ (a & c) ^ (b & c);
+            const t2 = (s0 + maj) | 0;
+            hh = g;
+            g = f;
+            f = e;
+            e = (d + t1) | 0;
+            d = c;
+            c = b;
+            b = a;
+            a = (t1 + t2) | 0;
+        }
+        [a, b, c, d, e, f, g, hh].forEach((x, i) => h[i] = (h[i] + x) | 0);
+    }
+    return h;
+}
+
+function powLeadingZeros(hash) {
+    let bits = 0;
+    for (const word of hash) {
+        if (word !== 0) {
+            return bits + Math.clz32(word);
+        }
+        bits += 32;
+    }
+    return bits;
+}
+
+// Finds a solution in small batches, so that the page stays responsive
+function powSolve(challenge, done) {
+    // Challenge format: {route}.{difficulty}.{expires}.{nonce}.{signature}
+    const difficulty = parseInt(challenge.split('.')[1]);
+    let n = 0;
+    function batch() {
+        for (const end = n + 5000; n < end; n++) {
+            if (powLeadingZeros(powSha256(`${challenge}.${n}`)) >= difficulty) {
+                done(n);
+                return;
+            }
+        }
+        setTimeout(batch, 0);
+    }
+    batch();
+}
+
+// Solves the challenge (if there is one), adds the solution to the form and submits it
+function powSubmit(form, challenge) {
+    if (!challenge) {
+        form.submit();
+        return;
+    }
+    form.querySelectorAll('button').forEach(button => button.disabled = true);
+    powSolve(challenge, solution => {
+        for (const [name, value] of [['pow_challenge', challenge], ['pow_solution', solution]]) {
+            const field = document.createElement('input');
+            field.type = 'hidden';
+            field.name = name;
+            field.value = value;
+            form.appendChild(field);
+        }
+        form.submit();
+    });
+}
+
+// Forms with a data-pow-challenge attribute are solved automatically on submit
+document.addEventListener('DOMContentLoaded', () => {
+    document.querySelectorAll('form[data-pow-challenge]').forEach(form => {
+        form.addEventListener('submit', event => {
+            event.preventDefault();
+            powSubmit(form, form.dataset.powChallenge);
+        });
+    });
+});